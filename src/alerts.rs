@@ -0,0 +1,358 @@
+// Alert rules that are evaluated against the state after each fetch, and delivered to webhooks
+use crate::api::v1::TrainJourney;
+use crate::membased::{Journey, Journeys};
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use fxhash::{FxHashMap, FxHashSet};
+use reqwest::{Client, ClientBuilder};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::{fs, time};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// The contents of the file passed to `--alert-rules`, in TOML.
+#[derive(Deserialize)]
+struct AlertConfig {
+    #[serde(default)]
+    webhooks: Vec<Webhook>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+struct Webhook {
+    name: String,
+    url: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Delayed,
+    PossiblyStuck,
+    Cancelled,
+}
+
+#[derive(Deserialize)]
+struct Rule {
+    name: String,
+    condition: Condition,
+    /// Only consider journeys from this data source, eg. VYG
+    data_source: Option<String>,
+    /// Only consider journeys on this line, either the entire LineRef or the last part of it, eg. R10
+    line: Option<String>,
+    /// Only consider journeys with this vehicle mode, eg. rail
+    mode: Option<String>,
+    /// For `delayed`, how late a journey must be to match
+    #[serde(default)]
+    min_delay_minutes: u32,
+    /// The rule fires when more than this many journeys match
+    #[serde(default)]
+    more_than: usize,
+    /// Names of the webhooks to notify. All webhooks are notified if empty.
+    #[serde(default)]
+    webhooks: Vec<String>,
+}
+
+impl Rule {
+    fn matches(&self, journey: &Journey) -> bool {
+        let condition = match self.condition {
+            Condition::Delayed => {
+                !journey.is_cancelled()
+                    && journey.recorded_delay_seconds() > self.min_delay_minutes as i32 * 60
+            }
+            Condition::PossiblyStuck => !journey.is_cancelled() && journey.possibly_stuck(),
+            Condition::Cancelled => journey.is_cancelled(),
+        };
+        let data_source = self
+            .data_source
+            .as_ref()
+            .is_none_or(|ds| ds == journey.data_source());
+        let line = self.line.as_ref().is_none_or(|line| journey.on_line(line));
+        let mode = self.mode.as_ref().is_none_or(|mode| mode == journey.mode());
+        condition && data_source && line && mode
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Firing,
+    Resolved,
+}
+
+/// The JSON body that is posted to a webhook
+#[derive(Serialize)]
+pub struct Notification {
+    pub rule: String,
+    pub status: Status,
+    pub condition: Condition,
+    pub matching: usize,
+    pub since: DateTime<Utc>,
+    pub at: DateTime<Utc>,
+    pub journeys: Vec<TrainJourney>,
+    #[serde(skip)]
+    url: String,
+}
+
+/// What each webhook has been told, keyed by rule name and webhook URL
+#[derive(Default)]
+struct Delivered {
+    /// When the rule started firing
+    firing: FxHashMap<(String, String), DateTime<Utc>>,
+    /// Notifications on their way, which we don't send again until we know how it went
+    pending: FxHashSet<(String, String)>,
+}
+
+/// Keeps track of which rules are firing, so that each rule notifies each webhook once when it
+/// starts firing and once when it resolves, rather than on every fetch. A webhook that doesn't
+/// answer with 2xx is notified again after the next fetch.
+pub struct Alerts {
+    config: AlertConfig,
+    client: Client,
+    delivered: Arc<Mutex<Delivered>>,
+}
+
+impl Alerts {
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let alerts = Self::from_toml(&fs::read_to_string(path)?)?;
+        info!(
+            "Loaded {} alert rules and {} webhooks from {path}",
+            alerts.config.rules.len(),
+            alerts.config.webhooks.len()
+        );
+        Ok(alerts)
+    }
+
+    fn from_toml(content: &str) -> anyhow::Result<Self> {
+        let config: AlertConfig = toml::from_str(content)?;
+
+        let webhooks: FxHashSet<_> = config.webhooks.iter().map(|w| w.name.as_str()).collect();
+        let mut rules = FxHashSet::default();
+        for rule in config.rules.iter() {
+            if !rules.insert(rule.name.as_str()) {
+                bail!("Duplicate alert rule name: {}", rule.name);
+            }
+            if let Some(missing) = rule
+                .webhooks
+                .iter()
+                .find(|w| !webhooks.contains(w.as_str()))
+            {
+                bail!(
+                    "Alert rule {} refers to unknown webhook {missing}",
+                    rule.name
+                );
+            }
+        }
+
+        let client = ClientBuilder::default()
+            .connect_timeout(time::Duration::from_millis(1_000))
+            .timeout(time::Duration::from_millis(5_000))
+            .build()?;
+
+        Ok(Self {
+            config,
+            client,
+            delivered: Arc::default(),
+        })
+    }
+
+    /// Evaluate all rules against `journeys`, returning notifications for the webhooks that
+    /// haven't been told about the current status of a rule yet
+    pub fn evaluate(&self, journeys: &Journeys) -> Vec<Notification> {
+        let now = Utc::now();
+        let mut notifications = Vec::new();
+        // PoisonError can only happen if a delivery panicked while updating this
+        let mut delivered = self.delivered.lock().unwrap();
+
+        for rule in self.config.rules.iter() {
+            let matching: Vec<_> = journeys.iter().filter(|j| rule.matches(j)).collect();
+            let fires = matching.len() > rule.more_than;
+            let webhooks = self
+                .config
+                .webhooks
+                .iter()
+                .filter(|w| rule.webhooks.is_empty() || rule.webhooks.contains(&w.name));
+            for webhook in webhooks {
+                let key = (rule.name.clone(), webhook.url.clone());
+                if delivered.pending.contains(&key) {
+                    continue;
+                }
+                let (status, since) = match (fires, delivered.firing.get(&key).copied()) {
+                    (true, None) => (Status::Firing, now),
+                    (false, Some(since)) => (Status::Resolved, since),
+                    _ => continue,
+                };
+                delivered.pending.insert(key);
+                notifications.push(Notification {
+                    rule: rule.name.clone(),
+                    status,
+                    condition: rule.condition,
+                    matching: matching.len(),
+                    since,
+                    at: now,
+                    journeys: matching.iter().map(|j| TrainJourney::from(*j)).collect(),
+                    url: webhook.url.clone(),
+                });
+            }
+        }
+
+        notifications
+    }
+
+    /// Post `notifications` in the background. A rule only counts as firing, or resolved, for a
+    /// webhook once it has answered with 2xx.
+    pub fn deliver(&self, notifications: Vec<Notification>) -> JoinHandle<()> {
+        let client = self.client.clone();
+        let delivered = self.delivered.clone();
+        tokio::spawn(async move {
+            for notification in notifications {
+                let response = client
+                    .post(&notification.url)
+                    .json(&notification)
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status());
+                let Notification {
+                    rule,
+                    status,
+                    since,
+                    url,
+                    ..
+                } = notification;
+                match response {
+                    Ok(_) => info!("Delivered alert {rule} to {url}"),
+                    Err(ref reason) => {
+                        error!("Unable to deliver alert {rule} to {url}: {reason:?}")
+                    }
+                }
+                let mut delivered = delivered.lock().unwrap();
+                let key = (rule, url);
+                delivered.pending.remove(&key);
+                match (response, status) {
+                    (Ok(_), Status::Firing) => {
+                        delivered.firing.insert(key, since);
+                    }
+                    (Ok(_), Status::Resolved) => {
+                        delivered.firing.remove(&key);
+                    }
+                    (Err(_), _) => {}
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::membased::JourneySource;
+    use crate::testing;
+    use axum::Json;
+    use axum::Router;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    /// A webhook that answers with `status` and remembers the status of each notification
+    #[derive(Clone, Default)]
+    struct Receiver {
+        status: Arc<AtomicU16>,
+        received: Arc<Mutex<Vec<String>>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        Json(body): Json<serde_json::Value>,
+    ) -> StatusCode {
+        receiver
+            .received
+            .lock()
+            .unwrap()
+            .push(body["status"].as_str().unwrap_or_default().to_string());
+        StatusCode::from_u16(receiver.status.load(Ordering::Relaxed)).unwrap()
+    }
+
+    impl Receiver {
+        async fn start() -> (Self, String) {
+            let receiver = Self::default();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/hook", listener.local_addr().unwrap());
+            let app = Router::new()
+                .route("/hook", post(receive))
+                .with_state(receiver.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            (receiver, url)
+        }
+
+        fn respond_with(&self, status: StatusCode) {
+            self.status.store(status.as_u16(), Ordering::Relaxed);
+        }
+    }
+
+    fn cancelled_journey() -> Journeys {
+        let in_an_hour = testing::minutes_from_now(60);
+        testing::journeys([(
+            "VYG:ServiceJourney:1",
+            JourneySource {
+                cancelled: true,
+                ..testing::source(
+                    "VYG:Line:R10",
+                    Vec::new(),
+                    vec![
+                        testing::call("Lillehammer", in_an_hour),
+                        testing::call("Drammen", in_an_hour + chrono::TimeDelta::hours(3)),
+                    ],
+                )
+            },
+        )])
+    }
+
+    #[tokio::test]
+    async fn fires_once_the_webhook_has_accepted_it() {
+        let (receiver, url) = Receiver::start().await;
+        let alerts = Alerts::from_toml(&format!(
+            r#"
+            [[webhooks]]
+            name = "local"
+            url = "{url}"
+
+            [[rules]]
+            name = "cancelled"
+            condition = "cancelled"
+            "#
+        ))
+        .unwrap();
+        let cancelled = cancelled_journey();
+        assert_eq!(cancelled.len(), 1);
+
+        receiver.respond_with(StatusCode::INTERNAL_SERVER_ERROR);
+        let notifications = alerts.evaluate(&cancelled);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].status, Status::Firing);
+        let delivery = alerts.deliver(notifications);
+        // Not sent again while it's on its way
+        assert!(alerts.evaluate(&cancelled).is_empty());
+        delivery.await.unwrap();
+
+        // The webhook didn't accept it, so it is sent again
+        receiver.respond_with(StatusCode::OK);
+        let notifications = alerts.evaluate(&cancelled);
+        assert_eq!(notifications.len(), 1);
+        alerts.deliver(notifications).await.unwrap();
+        assert!(alerts.evaluate(&cancelled).is_empty());
+
+        let notifications = alerts.evaluate(&Journeys::default());
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].status, Status::Resolved);
+        alerts.deliver(notifications).await.unwrap();
+        assert!(alerts.evaluate(&Journeys::default()).is_empty());
+
+        assert_eq!(
+            *receiver.received.lock().unwrap(),
+            vec!["firing", "firing", "resolved"]
+        );
+    }
+}
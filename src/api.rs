@@ -1,71 +1,151 @@
+pub mod v1;
+
+use crate::api::v1::{JourneyDetail, SourceQuality, TrainJourney};
+use crate::params::ListParams;
+use crate::resilience::FetchStatus;
 use askama::Template;
 use axum::Json;
 use axum::http::StatusCode;
+use axum::http::header::CACHE_CONTROL;
 use axum::response::{IntoResponse, Response};
 use chrono::{DateTime, FixedOffset, Utc};
 use chrono_tz::Europe::Oslo;
 use serde::Serialize;
-
-#[derive(Serialize)]
-pub struct JourneyDelay {
-    pub vehicle_journey_id: String,
-    pub line_ref: String,
-    pub last_stop_name: String,
-    pub aimed_last_stop_time: DateTime<FixedOffset>,
-    pub actual_last_stop_time: DateTime<FixedOffset>,
-    pub recorded_delay_seconds: i32,
-    pub next_stop_name: Option<String>,
-    pub aimed_next_stop_time: Option<DateTime<FixedOffset>>,
-}
-
-#[derive(Serialize)]
-pub struct TrainJourney {
-    pub vehicle_journey_id: String,
-    pub line_ref: String,
-    pub cancellation: bool,
-    pub data_source: String,
-    pub stop_name: String,
-    pub next_stop_name: Option<String>,
-    pub aimed_time: DateTime<FixedOffset>,
-    pub actual_time: DateTime<FixedOffset>,
-    pub delay_seconds: i32,
-    pub next_stop_time: Option<DateTime<FixedOffset>>,
-    pub departed: bool,
-    pub possibly_stuck: bool,
-}
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
 
 #[derive(Template)]
 #[template(path = "trains.html")]
 pub struct TrainsPage {
     pub trains: Vec<TrainJourney>,
+    pub total: usize,
     pub timestamp: String,
     pub delayed_count: usize,
     pub stuck_count: usize,
+    pub params: ListParams,
+    pub query: String,
+    pub prev_page: Option<String>,
+    pub next_page: Option<String>,
     pub assets_path: String,
 }
 
 impl TrainsPage {
-    pub fn new(trains: Vec<TrainJourney>, assets_path: String) -> Self {
+    /// `trains` should be filtered and sorted, but not paginated, so that we can count them.
+    pub fn new(trains: Vec<TrainJourney>, params: ListParams, assets_path: String) -> Self {
+        let total = trains.len();
         let delayed_count = trains.iter().filter(|t| t.delay_seconds > 60).count();
-        let stuck_count = trains.iter().filter(|t| t.possibly_stuck).count();
+        let stuck_count = trains
+            .iter()
+            .filter(|t| t.possibly_stuck && !t.data_stale)
+            .count();
         let now_oslo = Utc::now().with_timezone(&Oslo);
         let timestamp = now_oslo.format("%Y-%m-%d %H:%M:%S").to_string();
+        let (prev_page, next_page) = params.neighbours(total);
 
         Self {
-            trains,
+            trains: params.page(trains),
+            total,
             timestamp,
             delayed_count,
             stuck_count,
+            query: params.query_string(),
+            prev_page: prev_page.map(|p| p.query_string()),
+            next_page: next_page.map(|p| p.query_string()),
+            params,
+            assets_path,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "journey.html")]
+pub struct JourneyPage {
+    pub journey: JourneyDetail,
+    pub timestamp: String,
+    pub assets_path: String,
+}
+
+impl JourneyPage {
+    pub fn new(journey: JourneyDetail, assets_path: String) -> Self {
+        let now_oslo = Utc::now().with_timezone(&Oslo);
+        Self {
+            journey,
+            timestamp: now_oslo.format("%Y-%m-%d %H:%M:%S").to_string(),
             assets_path,
         }
     }
 }
 
-#[derive(Serialize)]
+#[derive(Template)]
+#[template(path = "quality.html")]
+pub struct QualityPage {
+    pub sources: Vec<SourceQuality>,
+    pub timestamp: String,
+    pub assets_path: String,
+}
+
+impl QualityPage {
+    pub fn new(sources: Vec<SourceQuality>, assets_path: String) -> Self {
+        let now_oslo = Utc::now().with_timezone(&Oslo);
+        Self {
+            sources,
+            timestamp: now_oslo.format("%Y-%m-%d %H:%M:%S").to_string(),
+            assets_path,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "loading.html")]
+pub struct LoadingPage {
+    pub assets_path: String,
+}
+
+pub struct FeedEntry {
+    pub id: String,
+    pub title: String,
+    pub summary: String,
+    pub updated: DateTime<FixedOffset>,
+}
+
+#[derive(Template)]
+#[template(path = "feed.atom", escape = "xml")]
+pub struct AtomFeed {
+    pub id: String,
+    pub title: String,
+    pub self_link: String,
+    pub updated: DateTime<FixedOffset>,
+    pub entries: Vec<FeedEntry>,
+}
+
+impl AtomFeed {
+    pub fn new(id: String, title: String, self_link: String, mut entries: Vec<FeedEntry>) -> Self {
+        entries.sort_by_key(|entry| Reverse(entry.updated));
+        let updated = entries
+            .first()
+            .map(|entry| entry.updated)
+            .unwrap_or_else(|| Utc::now().fixed_offset());
+        Self {
+            id,
+            title,
+            self_link,
+            updated,
+            entries,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 pub struct Healthy {
     pub last_successful_sync: Option<u32>,
     pub next_sync_attempt: Option<u32>,
+    /// No feed has its circuit breaker open
     pub healthy: bool,
+    /// The stop registry is from the local parquet cache, because the remote was unavailable
+    pub stops_from_cache: bool,
+    /// By feed name
+    pub fetch_status: BTreeMap<String, FetchStatus>,
 }
 
 impl IntoResponse for Healthy {
@@ -78,6 +158,36 @@ impl IntoResponse for Healthy {
     }
 }
 
+/// How fresh the data from a data source is
+#[derive(Serialize, ToSchema)]
+pub struct SourceFreshness {
+    /// eg. VYG
+    pub data_source: String,
+    /// Seconds since the newest `RecordedAtTime` among journeys from this data source
+    pub seconds_since_last_update: i64,
+    pub fresh: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    /// The initial import is done
+    pub loaded: bool,
+    /// Loaded, and some data source is fresh
+    pub ready: bool,
+    pub sources: Vec<SourceFreshness>,
+}
+
+impl IntoResponse for Readiness {
+    fn into_response(self) -> Response {
+        let status = if self.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, [(CACHE_CONTROL, "no-store")], Json(self)).into_response()
+    }
+}
+
 // Askama template filters
 mod filters {
     use chrono::{DateTime, FixedOffset};
@@ -88,8 +198,25 @@ mod filters {
         Ok(oslo_time.format("%H:%M").to_string())
     }
 
+    pub fn format_rfc3339(dt: &DateTime<FixedOffset>) -> ::askama::Result<String> {
+        Ok(dt.to_rfc3339())
+    }
+
     pub fn format_delay(seconds: &i32) -> ::askama::Result<String> {
         let minutes = seconds / 60;
         Ok(format!("{} min", minutes))
     }
+
+    pub fn format_percent(share: &f64) -> ::askama::Result<String> {
+        Ok(format!("{:.1} %", share * 100.0))
+    }
+
+    /// Whole seconds below two minutes, otherwise whole minutes
+    pub fn format_age(seconds: &i64) -> ::askama::Result<String> {
+        if *seconds < 120 {
+            Ok(format!("{seconds} s"))
+        } else {
+            Ok(format!("{} min", seconds / 60))
+        }
+    }
 }
@@ -0,0 +1,594 @@
+// Response types of the /api/v1 JSON API. These are part of a contract with consumers, so
+// fields must not be removed or change meaning. Add a new version of the API for that.
+use crate::membased::{self, Journey, Journeys, ModeSummary, StopPlace};
+use chrono::{DateTime, FixedOffset, Utc};
+use fxhash::FxHashSet;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// A journey that will visit a particular stop
+#[derive(Serialize, ToSchema)]
+pub struct JourneyDelay {
+    pub vehicle_journey_id: String,
+    /// The entire LineRef, eg. `VYG:Line:R10`
+    pub line_ref: String,
+    pub last_stop_name: String,
+    pub aimed_last_stop_time: DateTime<FixedOffset>,
+    pub actual_last_stop_time: DateTime<FixedOffset>,
+    /// Positive when the journey was late at the last stop, negative when early
+    pub recorded_delay_seconds: i32,
+    pub next_stop_name: Option<String>,
+    pub aimed_next_stop_time: Option<DateTime<FixedOffset>>,
+    /// The delay we expect at the next stop
+    pub next_stop_prediction: Option<DelayPrediction>,
+    /// Upcoming stops where the journey is expected at another platform than planned
+    pub platform_changes: Vec<PlatformChange>,
+    /// Some stops are cancelled, but not the whole journey
+    pub partially_cancelled: bool,
+    pub cancelled_stops: Vec<String>,
+    /// The journey terminates here, because the stops after it are cancelled
+    pub short_turned_at: Option<String>,
+}
+
+impl From<&Journey> for JourneyDelay {
+    fn from(value: &Journey) -> Self {
+        Self {
+            vehicle_journey_id: value.id().to_string(),
+            line_ref: value.line_ref().to_string(),
+            last_stop_name: value.prev_stop_name().to_string(),
+            aimed_last_stop_time: value.prev_stop_planned_time(),
+            actual_last_stop_time: value.prev_stop_actual_time(),
+            recorded_delay_seconds: value.recorded_delay_seconds(),
+            next_stop_name: value.next_stop_name().map(|name| name.to_string()),
+            aimed_next_stop_time: value.next_stop_planned_time(),
+            next_stop_prediction: value.next_prediction().map(DelayPrediction::from),
+            platform_changes: platform_changes(value),
+            partially_cancelled: value.is_partially_cancelled(),
+            cancelled_stops: value.cancelled_stops().to_vec(),
+            short_turned_at: value.short_turned_at().map(|stop| stop.to_string()),
+        }
+    }
+}
+
+/// The delay we expect at an upcoming stop
+#[derive(Serialize, ToSchema)]
+pub struct DelayPrediction {
+    pub stop_name: String,
+    pub aimed_time: DateTime<FixedOffset>,
+    /// The estimate from the producer, eg. Vy or Ruter
+    pub expected_delay_seconds: Option<i32>,
+    /// Our own estimate: the current delay, reduced by the slack in the schedule up to this stop
+    pub modelled_delay_seconds: i32,
+    /// The producer flagged its estimate as inaccurate or of low quality
+    pub unreliable: bool,
+    /// `expected_delay_seconds`, or `modelled_delay_seconds` when that is missing or unreliable
+    pub predicted_delay_seconds: i32,
+    /// `predicted_delay_seconds` is the producer's estimate
+    pub predicted_by_producer: bool,
+    /// `expected_delay_seconds` is more than a minute
+    pub expected_delayed: bool,
+}
+
+impl From<&membased::DelayPrediction> for DelayPrediction {
+    fn from(value: &membased::DelayPrediction) -> Self {
+        Self {
+            stop_name: value.stop_name.clone(),
+            aimed_time: value.aimed_time,
+            expected_delay_seconds: value.expected_delay_seconds,
+            modelled_delay_seconds: value.modelled_delay_seconds,
+            unreliable: value.unreliable,
+            predicted_delay_seconds: value.delay_seconds(),
+            predicted_by_producer: value.by_producer(),
+            expected_delayed: value.expected_delayed(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PlatformChange {
+    pub stop_name: String,
+    /// NSR quay id, eg. `NSR:Quay:1`
+    pub aimed_quay_ref: String,
+    pub expected_quay_ref: String,
+    /// Usually the track or platform number, when known
+    pub aimed_platform: Option<String>,
+    pub expected_platform: Option<String>,
+}
+
+impl PlatformChange {
+    /// Eg. `Oslo S: spor 1 → 2`, for the HTML pages
+    pub fn describe(&self) -> String {
+        match (&self.aimed_platform, &self.expected_platform) {
+            (Some(aimed), Some(expected)) => {
+                format!("{}: spor {aimed} → {expected}", self.stop_name)
+            }
+            (_, Some(expected)) => format!("{}: nytt spor {expected}", self.stop_name),
+            _ => format!("{}: nytt spor", self.stop_name),
+        }
+    }
+}
+
+fn platform_changes(journey: &Journey) -> Vec<PlatformChange> {
+    journey
+        .platform_changes()
+        .iter()
+        .map(|change| PlatformChange {
+            stop_name: change.stop_name.clone(),
+            aimed_quay_ref: change.aimed_quay_ref.clone(),
+            expected_quay_ref: change.expected_quay_ref.clone(),
+            aimed_platform: change.aimed_platform.clone(),
+            expected_platform: change.expected_platform.clone(),
+        })
+        .collect()
+}
+
+/// Refers to another journey, eg. the extra journey that replaces a cancelled one
+#[derive(Serialize, ToSchema)]
+pub struct JourneyLink {
+    pub vehicle_journey_id: String,
+    /// Short line name, origin and destination, eg. `R10: Drammen to Lillehammer`
+    pub line_ref: String,
+    /// The vehicle mode, eg. `bus`
+    pub mode: String,
+}
+
+impl From<&membased::JourneyLink> for JourneyLink {
+    fn from(value: &membased::JourneyLink) -> Self {
+        Self {
+            vehicle_journey_id: value.id.clone(),
+            line_ref: value.line.clone(),
+            mode: value.mode.clone(),
+        }
+    }
+}
+
+/// A train journey that is currently in traffic
+#[derive(Serialize, ToSchema)]
+pub struct TrainJourney {
+    pub vehicle_journey_id: String,
+    /// Short line name, origin and destination, eg. `R10: Drammen to Lillehammer`
+    pub line_ref: String,
+    pub cancellation: bool,
+    /// The operator, eg. VYG
+    pub data_source: String,
+    /// The name of the feed we received the journey from
+    pub feed: String,
+    /// The last stop the train visited, or its first stop when it hasn't departed
+    pub stop_name: String,
+    pub next_stop_name: Option<String>,
+    pub aimed_time: DateTime<FixedOffset>,
+    /// When the train left `stop_name`, or is expected to leave it when it hasn't departed
+    pub actual_time: DateTime<FixedOffset>,
+    /// Positive when the train was late at `stop_name`, negative when early
+    pub delay_seconds: i32,
+    pub next_stop_time: Option<DateTime<FixedOffset>>,
+    /// The delay we expect at the next stop
+    pub next_stop_prediction: Option<DelayPrediction>,
+    /// The train has visited at least one stop. Cancelled and extra journeys are included
+    /// before they depart.
+    pub departed: bool,
+    /// The train should have reached the next stop a while ago. See `data_stale` for whether
+    /// the train itself is likely stuck.
+    pub possibly_stuck: bool,
+    /// Only set along with `possibly_stuck`, when the producer hasn't sent recent data for the
+    /// train, or doesn't track it in real time
+    pub data_stale: bool,
+    /// Explains `possibly_stuck` or `data_stale`, in Norwegian
+    pub stuck_reason: Option<String>,
+    /// Upcoming stops where the train is expected at another platform than planned
+    pub platform_changes: Vec<PlatformChange>,
+    /// Some stops are cancelled, but not the whole journey
+    pub partially_cancelled: bool,
+    pub cancelled_stops: Vec<String>,
+    /// The train terminates here, because the stops after it are cancelled
+    pub short_turned_at: Option<String>,
+    /// Not in the original plan, usually a replacement service such as buss for tog
+    pub extra_journey: bool,
+    /// Extra journeys that replace this one, when it is cancelled
+    pub replaced_by: Vec<JourneyLink>,
+    /// Cancelled journeys that this extra journey replaces
+    pub replaces: Vec<JourneyLink>,
+}
+
+impl From<&Journey> for TrainJourney {
+    fn from(value: &Journey) -> Self {
+        Self {
+            vehicle_journey_id: value.id().to_string(),
+            line_ref: value.describe_line(),
+            cancellation: value.is_cancelled(),
+            data_source: value.data_source().to_string(),
+            feed: value.feed().to_string(),
+            stop_name: value.prev_stop_name().to_string(),
+            next_stop_name: value.next_stop_name().map(|name| name.to_string()),
+            aimed_time: value.prev_stop_planned_time(),
+            actual_time: value.prev_stop_actual_time(),
+            delay_seconds: value.recorded_delay_seconds(),
+            next_stop_time: value.next_stop_planned_time(),
+            next_stop_prediction: value.next_prediction().map(DelayPrediction::from),
+            departed: value.departed(),
+            possibly_stuck: value.overdue(),
+            data_stale: value.data_stale(),
+            stuck_reason: value.stuck_status().map(|status| status.reason(value)),
+            platform_changes: platform_changes(value),
+            partially_cancelled: value.is_partially_cancelled(),
+            cancelled_stops: value.cancelled_stops().to_vec(),
+            short_turned_at: value.short_turned_at().map(|stop| stop.to_string()),
+            extra_journey: value.is_extra(),
+            replaced_by: value.replaced_by().iter().map(JourneyLink::from).collect(),
+            replaces: value.replaces().iter().map(JourneyLink::from).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PlannedStop {
+    pub name: String,
+    pub cancelled: bool,
+}
+
+/// Everything we know about a single journey
+#[derive(Serialize, ToSchema)]
+pub struct JourneyDetail {
+    #[serde(flatten)]
+    pub journey: TrainJourney,
+    /// The vehicle mode, eg. `rail` or `bus`
+    pub mode: String,
+    /// Every stop of the journey, in order
+    pub stops: Vec<PlannedStop>,
+    /// The delay we expect at each upcoming stop that isn't cancelled
+    pub predictions: Vec<DelayPrediction>,
+}
+
+impl From<&Journey> for JourneyDetail {
+    fn from(value: &Journey) -> Self {
+        let cancelled: FxHashSet<_> = value.cancelled_stops().iter().collect();
+        Self {
+            journey: value.into(),
+            mode: value.mode().to_string(),
+            stops: value
+                .planned_stops()
+                .iter()
+                .map(|name| PlannedStop {
+                    name: name.clone(),
+                    cancelled: value.is_cancelled() || cancelled.contains(name),
+                })
+                .collect(),
+            predictions: value
+                .predictions()
+                .iter()
+                .map(DelayPrediction::from)
+                .collect(),
+        }
+    }
+}
+
+/// A stop place matching a search
+#[derive(Serialize, ToSchema)]
+pub struct StopPlaceMatch {
+    /// NSR id, eg. `NSR:StopPlace:337`
+    pub id: String,
+    pub name: String,
+    pub short_name: Option<String>,
+    pub public_code: Option<String>,
+    pub alternative_names: Vec<String>,
+    pub lat: Option<f32>,
+    pub lon: Option<f32>,
+    /// Transport modes serving the stop place or the stop places below it, eg. `rail` or `bus`
+    pub modes: Vec<String>,
+}
+
+impl From<&StopPlace> for StopPlaceMatch {
+    fn from(value: &StopPlace) -> Self {
+        Self {
+            id: value.id.clone(),
+            name: value.name.clone(),
+            short_name: value.short_name.clone(),
+            public_code: value.public_code.clone(),
+            alternative_names: value.alternative_names.clone(),
+            lat: value.lat.map(|lat| lat.0),
+            lon: value.lon.map(|lon| lon.0),
+            modes: value.modes.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StopLevel {
+    Quay,
+    StopPlace,
+    /// A multimodal parent station, with other stop places below it
+    Parent,
+}
+
+impl From<membased::StopLevel> for StopLevel {
+    fn from(value: membased::StopLevel) -> Self {
+        match value {
+            membased::StopLevel::Quay => Self::Quay,
+            membased::StopLevel::StopPlace => Self::StopPlace,
+            membased::StopLevel::Parent => Self::Parent,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StopPlaceRef {
+    pub id: String,
+    pub name: String,
+}
+
+impl From<&StopPlace> for StopPlaceRef {
+    fn from(value: &StopPlace) -> Self {
+        Self {
+            id: value.id.clone(),
+            name: value.name.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct QuayRef {
+    /// NSR id, eg. `NSR:Quay:1`, which is the StopPointRef used in journeys
+    pub id: String,
+    /// Usually the platform or track number
+    pub public_code: Option<String>,
+}
+
+/// Counts of journeys that will visit a stop
+#[derive(Serialize, ToSchema)]
+pub struct StopSummary {
+    pub journeys: usize,
+    /// Delayed more than a minute at the last stop
+    pub delayed: usize,
+    /// Overdue journeys, including those we lack recent data for
+    pub possibly_stuck: usize,
+    /// Overdue journeys we lack recent data for
+    pub data_stale: usize,
+    pub cancelled: usize,
+}
+
+impl From<ModeSummary> for StopSummary {
+    fn from(value: ModeSummary) -> Self {
+        Self {
+            journeys: value.journeys,
+            delayed: value.delayed,
+            possibly_stuck: value.possibly_stuck + value.data_stale,
+            data_stale: value.data_stale,
+            cancelled: value.cancelled,
+        }
+    }
+}
+
+/// A quay, stop place or parent station, with the quays below it and the journeys visiting them
+#[derive(Serialize, ToSchema)]
+pub struct StopArea {
+    pub id: String,
+    pub name: String,
+    pub level: StopLevel,
+    /// The stop place of a quay, or the parent station of a stop place
+    pub parent: Option<StopPlaceRef>,
+    /// The stop places below a parent station
+    pub children: Vec<StopPlaceRef>,
+    /// Every quay at this level or below
+    pub quays: Vec<QuayRef>,
+    pub summary: StopSummary,
+}
+
+impl StopArea {
+    pub fn new(area: &membased::StopArea, summary: ModeSummary) -> Self {
+        Self {
+            id: area.id.clone(),
+            name: area.name.clone(),
+            level: area.level.into(),
+            parent: area.parent.map(StopPlaceRef::from),
+            children: area
+                .children
+                .iter()
+                .map(|child| StopPlaceRef::from(*child))
+                .collect(),
+            quays: area
+                .quays
+                .iter()
+                .map(|quay| QuayRef {
+                    id: quay.id.clone(),
+                    public_code: quay.public_code.clone(),
+                })
+                .collect(),
+            summary: summary.into(),
+        }
+    }
+}
+
+/// Data quality for one data source in one feed, to help tell real delays from data problems
+#[derive(Serialize, ToSchema)]
+pub struct SourceQuality {
+    /// The name of the feed, eg. `default`
+    pub feed: String,
+    /// eg. VYG
+    pub data_source: String,
+    /// When we last received journeys from this data source in this feed. The counts below are
+    /// from then.
+    pub received_at: DateTime<FixedOffset>,
+    pub journeys_received: usize,
+    /// Share of journeys the producer doesn't track in real time, between 0 and 1
+    pub unmonitored_share: f64,
+    /// Share of journeys where the producer flagged the journey or any call as inaccurate
+    pub prediction_inaccurate_share: f64,
+    /// Journeys that have started, but we couldn't use, because they lack an id, times or stops
+    /// we can name. Journeys that haven't started are left out on purpose.
+    pub journeys_dropped: usize,
+    pub calls: usize,
+    /// Calls with a StopPointRef that isn't in the stop registry
+    pub unknown_stop_refs: usize,
+    /// How old `RecordedAtTime` was when we received the journeys, on average
+    pub mean_age_seconds: i64,
+    pub max_age_seconds: i64,
+    /// Journeys in state from this data source that we last received from this feed
+    pub journeys_in_state: usize,
+    /// Seconds since the newest `RecordedAtTime` among those journeys
+    pub seconds_since_last_update: Option<i64>,
+}
+
+impl SourceQuality {
+    pub fn report(journeys: &Journeys) -> Vec<Self> {
+        let now = Utc::now().fixed_offset();
+        let in_state = journeys.count_by_feed_and_data_source();
+        let share = |count: usize, total: usize| count as f64 / total.max(1) as f64;
+        let mut report: Vec<_> = journeys
+            .quality()
+            .map(|(feed, data_source, quality)| Self {
+                feed: feed.to_string(),
+                data_source: data_source.to_string(),
+                received_at: quality.at,
+                journeys_received: quality.received,
+                unmonitored_share: share(quality.unmonitored, quality.received),
+                prediction_inaccurate_share: share(quality.prediction_inaccurate, quality.received),
+                journeys_dropped: quality.dropped,
+                calls: quality.calls,
+                unknown_stop_refs: quality.unknown_stop_refs,
+                mean_age_seconds: quality.mean_age_seconds(),
+                max_age_seconds: quality.max_age_seconds,
+                journeys_in_state: in_state
+                    .get(&(feed, data_source))
+                    .map_or(0, |(count, _)| *count),
+                seconds_since_last_update: in_state
+                    .get(&(feed, data_source))
+                    .map(|(_, newest)| (now - *newest).num_seconds()),
+            })
+            .collect();
+        report.sort_by(|a, b| {
+            (a.data_source.as_str(), a.feed.as_str())
+                .cmp(&(b.data_source.as_str(), b.feed.as_str()))
+        });
+        report
+    }
+}
+
+/// A StopPointRef from SIRI that isn't in the stop registry
+#[derive(Serialize, ToSchema)]
+pub struct UnresolvedStopRef {
+    /// eg. `NSR:Quay:1234`
+    pub stop_point_ref: String,
+    pub calls: usize,
+    /// The StopPointName we show instead. Calls are dropped when there is none.
+    pub fallback_name: Option<String>,
+    pub data_sources: Vec<String>,
+    /// A few of the journeys with calls at this StopPointRef
+    pub example_journeys: Vec<String>,
+}
+
+/// Counted over every fetch since the server started
+#[derive(Serialize, ToSchema)]
+pub struct UnresolvedStops {
+    /// Calls that have no StopPointRef at all, these are dropped
+    pub calls_without_stop_point_ref: usize,
+    /// Most frequent first
+    pub stop_point_refs: Vec<UnresolvedStopRef>,
+}
+
+impl From<&membased::UnresolvedStops> for UnresolvedStops {
+    fn from(unresolved: &membased::UnresolvedStops) -> Self {
+        Self {
+            calls_without_stop_point_ref: unresolved.calls_without_ref,
+            stop_point_refs: unresolved
+                .by_count()
+                .into_iter()
+                .map(|(stop_point_ref, stop)| UnresolvedStopRef {
+                    stop_point_ref: stop_point_ref.to_string(),
+                    calls: stop.calls,
+                    fallback_name: stop.fallback_name.clone(),
+                    data_sources: stop.data_sources.clone(),
+                    example_journeys: stop.example_journeys.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::membased::JourneySource;
+    use crate::testing;
+    use chrono::TimeDelta;
+
+    #[test]
+    fn extra_journey_that_hasnt_departed_is_at_its_first_stop() {
+        let departure = testing::minutes_from_now(30);
+        let journeys = testing::journeys([(
+            "VYG:ServiceJourney:extra",
+            JourneySource {
+                extra: true,
+                ..testing::source(
+                    "VYG:Line:R10",
+                    Vec::new(),
+                    vec![
+                        testing::call("Oslo S", departure),
+                        testing::call("Lillestrøm", departure + TimeDelta::minutes(12)),
+                    ],
+                )
+            },
+        )]);
+        let train = TrainJourney::from(journeys.get("VYG:ServiceJourney:extra").unwrap());
+
+        assert!(!train.departed);
+        assert!(train.extra_journey);
+        assert!(!train.cancellation);
+        assert_eq!(train.stop_name, "Oslo S");
+        assert_eq!(train.aimed_time, departure);
+        assert_eq!(train.actual_time, departure);
+        assert_eq!(train.delay_seconds, 0);
+        assert_eq!(train.next_stop_name.as_deref(), Some("Oslo S"));
+        assert!(!train.possibly_stuck);
+        assert!(!train.data_stale);
+    }
+
+    #[test]
+    fn journey_with_recorded_calls_has_departed() {
+        let departure = testing::minutes_from_now(-10);
+        let journeys = testing::journeys([(
+            "VYG:ServiceJourney:1",
+            testing::source(
+                "VYG:Line:R10",
+                vec![testing::visited("Oslo S", departure, TimeDelta::minutes(2))],
+                vec![testing::call(
+                    "Lillestrøm",
+                    departure + TimeDelta::minutes(12),
+                )],
+            ),
+        )]);
+        let train = TrainJourney::from(journeys.get("VYG:ServiceJourney:1").unwrap());
+
+        assert!(train.departed);
+        assert_eq!(train.stop_name, "Oslo S");
+        assert_eq!(train.delay_seconds, 120);
+        assert_eq!(train.next_stop_name.as_deref(), Some("Lillestrøm"));
+    }
+
+    #[test]
+    fn overdue_journeys_are_possibly_stuck_even_when_the_data_is_stale() {
+        let next = testing::minutes_from_now(-20);
+        let source = testing::source(
+            "VYG:Line:R10",
+            vec![testing::visited(
+                "Oslo S",
+                next - TimeDelta::minutes(12),
+                TimeDelta::zero(),
+            )],
+            vec![testing::call("Lillestrøm", next)],
+        );
+        let stale = JourneySource {
+            recorded_at_time: testing::minutes_from_now(-15),
+            ..source.clone()
+        };
+        let journeys = testing::journeys([("recent", source), ("stale", stale)]);
+
+        let recent = TrainJourney::from(journeys.get("recent").unwrap());
+        assert!(recent.possibly_stuck);
+        assert!(!recent.data_stale);
+        let stale = TrainJourney::from(journeys.get("stale").unwrap());
+        assert!(stale.possibly_stuck);
+        assert!(stale.data_stale);
+        assert!(stale.stuck_reason.is_some());
+    }
+}
@@ -0,0 +1,63 @@
+// Measures how long it takes, and how much memory we need, to turn a SIRI ET file into journeys
+use crate::cli::BenchOptions;
+use crate::ingest::{self, IngestFilter};
+use crate::membased::{JourneysBuilder, Stops};
+use crate::stuck::StuckConfig;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub fn run(options: BenchOptions) -> anyhow::Result<()> {
+    let BenchOptions {
+        file,
+        buffered,
+        ingest_options,
+    } = options;
+    let filter = IngestFilter::from(ingest_options);
+    // Without the stop registry, stops get their names from SIRI, which is fine for measuring
+    let stops = Stops::new(Vec::new(), Vec::new());
+    let stuck = StuckConfig::default();
+    let feed: Arc<str> = Arc::from("bench");
+
+    let started = Instant::now();
+    let builder = JourneysBuilder::new(&stops, &stuck, feed);
+    // Both build the journeys the same way, buffered just holds the whole document while doing it
+    let journeys = if buffered {
+        let content = fs::read(&file)?;
+        ingest::parse(content.as_slice(), &filter, builder)?.journeys
+    } else {
+        let reader = BufReader::with_capacity(256 * 1024, File::open(&file)?);
+        ingest::parse(reader, &filter, builder)?.journeys
+    };
+    let elapsed = started.elapsed();
+
+    // What we use now is mostly the journeys we keep, the rest of the peak is spent parsing
+    println!(
+        "mode={} journeys={} seconds={:.2} rss_mb={} peak_rss_mb={}",
+        if buffered { "buffered" } else { "streaming" },
+        journeys.len(),
+        elapsed.as_secs_f64(),
+        megabytes(rss_kb("VmRSS:")),
+        megabytes(rss_kb("VmHWM:")),
+    );
+    Ok(())
+}
+
+fn megabytes(kb: Option<u64>) -> String {
+    kb.map_or_else(|| "unknown".to_string(), |kb| (kb / 1024).to_string())
+}
+
+/// Memory use from /proc, so only on Linux. `VmRSS:` is what we use now, `VmHWM:` the most we
+/// have used.
+fn rss_kb(field: &str) -> Option<u64> {
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(field))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
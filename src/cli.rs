@@ -1,4 +1,5 @@
 // CLI argument definitions
+use crate::ingest::BoundingBox;
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
@@ -16,6 +17,14 @@ pub struct SharedOptions {
         default_value = "https://kaaveland-bus-eta-data.hel1.your-objectstorage.com/"
     )]
     pub parquet_root: String,
+    /// Keep the parquet files from --parquet-root in this directory, and use them when
+    /// --parquet-root is unavailable. Files are only downloaded again when they have changed.
+    #[arg(long = "parquet-cache-dir")]
+    pub parquet_cache_dir: Option<String>,
+    /// Copy quays.parquet and stops.parquet from this directory into --parquet-cache-dir on
+    /// startup, unless the cache already has them.
+    #[arg(long = "seed-parquet-cache")]
+    pub seed_parquet_cache: Option<String>,
     /// DuckDB to connect to - uses an inmemory-db if not configured.
     #[arg(short = 'd', long = "db-url")]
     pub db_url: Option<String>,
@@ -31,21 +40,113 @@ pub struct SharedOptions {
     pub memory_gb: u8,
 }
 
+#[derive(Parser)]
+pub struct RetryOptions {
+    /// How many times to try each fetch, before giving up until the next fetch interval
+    #[arg(long = "fetch-attempts", default_value = "3")]
+    pub fetch_attempts: u32,
+    /// Milliseconds to wait before the first retry, doubling for every retry after that
+    #[arg(long = "fetch-retry-base-ms", default_value = "500")]
+    pub fetch_retry_base_ms: u64,
+    /// Never wait longer than this many milliseconds between retries
+    #[arg(long = "fetch-retry-max-ms", default_value = "30000")]
+    pub fetch_retry_max_ms: u64,
+    /// Stop fetching for a while after this many fetches in a row have failed
+    #[arg(long = "circuit-breaker-failures", default_value = "5")]
+    pub circuit_breaker_failures: u32,
+    /// Seconds to stop fetching for when the circuit breaker opens
+    #[arg(long = "circuit-breaker-cooldown-seconds", default_value = "120")]
+    pub circuit_breaker_cooldown_seconds: u64,
+}
+
+/// Which journeys to keep. They apply to every feed, and the others are left out while parsing.
+#[derive(Parser)]
+pub struct IngestOptions {
+    /// Only keep journeys from these data sources, eg. VYG,BNR
+    #[arg(long = "ingest-data-sources", value_delimiter = ',')]
+    pub ingest_data_sources: Option<Vec<String>>,
+    /// Only keep journeys on these lines, either the entire LineRef or the last part of it, eg. R10,L1
+    #[arg(long = "ingest-lines", value_delimiter = ',')]
+    pub ingest_lines: Option<Vec<String>>,
+    /// Only keep journeys with these vehicle modes, eg. rail
+    #[arg(long = "ingest-modes", value_delimiter = ',')]
+    pub ingest_modes: Option<Vec<String>>,
+    /// Only keep journeys that call at a quay inside min_lat,min_lon,max_lat,max_lon, eg.
+    /// 59.0,10.0,60.5,11.5
+    #[arg(long = "ingest-bbox")]
+    pub ingest_bbox: Option<BoundingBox>,
+}
+
+#[derive(Parser)]
+pub struct ServeOptions {
+    #[command(flatten)]
+    pub shared_options: SharedOptions,
+    #[command(flatten)]
+    pub retry_options: RetryOptions,
+    #[command(flatten)]
+    pub ingest_options: IngestOptions,
+    /// Host the webapp on this particular port
+    #[arg(short = 'p', long = "port", default_value = "4500")]
+    pub port: u16,
+    /// Check for new data every fetch-interval seconds. If not provided, never refetch.
+    #[arg(short = 'i', long = "fetch-interval-seconds")]
+    pub fetch_interval_seconds: Option<u16>,
+    /// TOML file with the feeds to fetch from, each with its own URL, interval, requestorId and
+    /// data sources. Replaces --api-url, --static-data, --requestor-id and
+    /// --fetch-interval-seconds.
+    #[arg(long = "feeds")]
+    pub feeds: Option<String>,
+    /// /readyz fails when no data source has sent us data for this many seconds. Only applies
+    /// when some feed is fetched periodically.
+    #[arg(long = "ready-max-age-seconds", default_value = "600")]
+    pub ready_max_age_seconds: u32,
+    #[arg(long = "assets-path", default_value = "/static")]
+    pub assets_path: String,
+    /// Reload quays.parquet and stops.parquet from --parquet-root every stop-refresh-minutes
+    /// minutes, and resolve the stops of every journey again. If not provided, never reload.
+    #[arg(long = "stop-refresh-minutes")]
+    pub stop_refresh_minutes: Option<u32>,
+    /// Save the journeys to this file periodically and on shutdown, and start from it on boot so
+    /// that we can serve right away. The first fetch then catches up with what we missed.
+    #[arg(long = "snapshot-path")]
+    pub snapshot_path: Option<String>,
+    /// How often to save the snapshot, when --snapshot-path is set
+    #[arg(long = "snapshot-interval-minutes", default_value = "5")]
+    pub snapshot_interval_minutes: u32,
+    /// TOML file with alert rules and webhooks to notify. Rules are evaluated after each fetch,
+    /// so this requires a feed that is fetched periodically.
+    #[arg(long = "alert-rules")]
+    pub alert_rules: Option<String>,
+    /// TOML file with thresholds for when journeys are considered possibly stuck, or their data
+    /// stale, per mode and data source.
+    #[arg(long = "stuck-rules")]
+    pub stuck_rules: Option<String>,
+    /// Log the StopPointRefs that aren't in the stop registry once per fetch
+    #[arg(long = "log-unresolved-stops")]
+    pub log_unresolved_stops: bool,
+}
+
+#[derive(Parser)]
+pub struct BenchOptions {
+    /// A SIRI ET file, eg. downloaded from the Entur API with curl
+    pub file: String,
+    /// Read the whole file into memory before parsing it, the way we did with the response body
+    /// before parsing while reading
+    #[arg(long = "buffered")]
+    pub buffered: bool,
+    #[command(flatten)]
+    pub ingest_options: IngestOptions,
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Start a long-lived http server that continually imports data
-    Serve {
-        #[command(flatten)]
-        shared_options: SharedOptions,
-        /// Host the webapp on this particular port
-        #[arg(short = 'p', long = "port", default_value = "4500")]
-        port: u16,
-        /// Check for new data every fetch-interval seconds. If not provided, never refetch.
-        #[arg(short = 'i', long = "fetch-interval-seconds")]
-        fetch_interval_seconds: Option<u16>,
-        #[arg(long = "assets-path", default_value = "/static")]
-        assets_path: String,
-    },
+    Serve(Box<ServeOptions>),
+    /// Print the OpenAPI specification of the JSON API
+    Openapi,
+    /// Build the journeys in a SIRI ET file like a fetch would, and report how long it took and
+    /// the peak memory use
+    Bench(BenchOptions),
 }
 
 #[derive(Parser)]
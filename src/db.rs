@@ -1,4 +1,5 @@
 use duckdb::Connection;
+use duckdb::types::Value;
 use ordered_float::OrderedFloat;
 use tracing::info;
 
@@ -9,6 +10,8 @@ from stops s
 select
   s.name as name,
   q.id as stop_point_ref,
+  q.stopPlaceRef as stop_place_ref,
+  q.publicCode as public_code,
   coalesce(q.location_latitude, s.location_latitude) as lat,
   coalesce(q.location_longitude, s.location_longitude) as lon
 ";
@@ -16,21 +19,75 @@ select
 pub struct StopRow {
     pub name: String,
     pub stop_point_ref: String,
+    pub stop_place_ref: String,
+    pub public_code: Option<String>,
     pub lat: Option<OrderedFloat<f32>>,
     pub lon: Option<OrderedFloat<f32>>,
 }
 
 pub fn read_stops(db: &Connection) -> duckdb::Result<Vec<StopRow>> {
-    db.prepare("from stopdata select name, stop_point_ref, lat, lon where name is not null")?
-        .query_map([], |row| {
-            Ok(StopRow {
-                name: row.get(0)?,
-                stop_point_ref: row.get(1)?,
-                lat: row.get::<_, Option<f32>>(2)?.map(OrderedFloat),
-                lon: row.get::<_, Option<f32>>(3)?.map(OrderedFloat),
-            })
-        })?
-        .collect()
+    db.prepare(
+        "from stopdata select name, stop_point_ref, stop_place_ref, public_code, lat, lon
+         where name is not null",
+    )?
+    .query_map([], |row| {
+        Ok(StopRow {
+            name: row.get(0)?,
+            stop_point_ref: row.get(1)?,
+            stop_place_ref: row.get(2)?,
+            public_code: row.get(3)?,
+            lat: row.get::<_, Option<f32>>(4)?.map(OrderedFloat),
+            lon: row.get::<_, Option<f32>>(5)?.map(OrderedFloat),
+        })
+    })?
+    .collect()
+}
+
+pub struct StopPlaceRow {
+    pub id: String,
+    pub name: String,
+    pub short_name: Option<String>,
+    pub public_code: Option<String>,
+    pub transport_mode: Option<String>,
+    pub alternative_names: Vec<String>,
+    /// The multimodal parent station, eg. `NSR:StopPlace:59872` for Oslo S
+    pub parent_ref: Option<String>,
+    pub lat: Option<OrderedFloat<f32>>,
+    pub lon: Option<OrderedFloat<f32>>,
+}
+
+pub fn read_stop_places(db: &Connection) -> duckdb::Result<Vec<StopPlaceRow>> {
+    db.prepare(
+        "from stops select
+           id, name, shortName, publicCode, transportMode,
+           list_transform(alternativeNames, alt -> alt.name), parentRef.ref,
+           location_latitude, location_longitude
+         where name is not null",
+    )?
+    .query_map([], |row| {
+        let alternative_names = match row.get::<_, Value>(5)? {
+            Value::List(names) => names
+                .into_iter()
+                .filter_map(|name| match name {
+                    Value::Text(name) => Some(name),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(StopPlaceRow {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            short_name: row.get(2)?,
+            public_code: row.get(3)?,
+            transport_mode: row.get(4)?,
+            alternative_names,
+            parent_ref: row.get(6)?,
+            lat: row.get::<_, Option<f32>>(7)?.map(OrderedFloat),
+            lon: row.get::<_, Option<f32>>(8)?.map(OrderedFloat),
+        })
+    })?
+    .collect()
 }
 
 pub fn prepare_db(
@@ -41,7 +98,7 @@ pub fn prepare_db(
 ) -> anyhow::Result<Connection> {
     info!("Prepare database {:?}", db_url);
 
-    let db = match db_url {
+    let mut db = match db_url {
         None => Connection::open_in_memory(),
         Some(f) => Connection::open(f.as_str()),
     }?;
@@ -58,18 +115,27 @@ pub fn prepare_db(
 
     db.execute_batch(schema)?;
 
+    load_stop_data(&mut db, parquet_root)?;
+
+    Ok(db)
+}
+
+/// Replace the stop registry with `quays.parquet` and `stops.parquet` from `parquet_root`. This
+/// happens in a transaction, so the old data is kept when anything fails.
+pub fn load_stop_data(db: &mut Connection, parquet_root: &str) -> anyhow::Result<()> {
     let quays = format!("{}/quays.parquet", parquet_root.trim_end_matches('/'));
     let stops = format!("{}/stops.parquet", parquet_root.trim_end_matches('/'));
     info!("Create quays={quays} and stops={stops} in DuckDB");
-    db.execute(
+    let tx = db.transaction()?;
+    tx.execute(
         "create or replace table quays as from read_parquet($1);",
         [quays.as_str()],
     )?;
-    db.execute(
+    tx.execute(
         "create or replace table stops as from read_parquet($1);",
         [stops.as_str()],
     )?;
-    db.execute_batch(STOP_DATA)?;
-
-    Ok(db)
+    tx.execute_batch(STOP_DATA)?;
+    tx.commit()?;
+    Ok(())
 }
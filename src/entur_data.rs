@@ -1,15 +1,31 @@
-use crate::entur_siriformat::SiriETResponse;
-use reqwest::Client;
-use std::fs;
+use crate::metrics::Metrics;
+use futures_util::TryStreamExt;
+use reqwest::{Client, Response};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_util::io::{StreamReader, SyncIoBridge};
 use tracing::{info, instrument};
 
 pub const ENTUR_API_URL: &str = "https://api.entur.io/realtime/v1/rest/et";
 
+/// What upstream sends us
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// SIRI ET as JSON, the way the Entur API delivers it
+    #[default]
+    SiriJson,
+}
+
 pub struct Config {
     requestor_id: String,
     api_url: String,
     client: Client,
     static_data: Option<String>,
+    format: Format,
 }
 
 impl Config {
@@ -18,18 +34,20 @@ impl Config {
         api_url: String,
         client: Client,
         static_data: Option<String>,
+        format: Format,
     ) -> Self {
         Self {
             requestor_id,
             api_url,
             client,
             static_data,
+            format,
         }
     }
 }
 
 #[instrument(name = "fetch_siri", skip(config))]
-async fn fetch_siri(config: &Config) -> anyhow::Result<SiriETResponse> {
+async fn fetch_siri(config: &Config) -> anyhow::Result<Response> {
     let url = config.api_url.as_str();
     let requestor_id = config.requestor_id.as_str();
     info!("Poll {url} with requestorId={requestor_id}");
@@ -42,15 +60,63 @@ async fn fetch_siri(config: &Config) -> anyhow::Result<SiriETResponse> {
         .header("Accept", "application/json")
         .send()
         .await?
-        .json()
-        .await?)
+        .error_for_status()?)
 }
 
-pub async fn fetch_data(config: &Config) -> anyhow::Result<SiriETResponse> {
-    if let Some(path) = &config.static_data {
-        let content = fs::read(path)?;
-        Ok(serde_json::from_slice(&content)?)
-    } else {
-        fetch_siri(config).await
+/// Counts the bytes we read
+struct CountingReader<R> {
+    inner: R,
+    bytes: usize,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes += read;
+        Ok(read)
     }
 }
+
+/// Read the SIRI data as it arrives, and hand it to `parse` on a blocking thread, so that we never
+/// hold the entire response in memory. `fetch_duration_seconds` is the time until upstream starts
+/// answering, while `parse_duration_seconds` covers reading the rest of it while we parse.
+pub async fn fetch_data<T, P>(
+    config: &Config,
+    metrics: &Arc<Metrics>,
+    parse: P,
+) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    P: FnOnce(Format, &mut dyn Read) -> anyhow::Result<T> + Send + 'static,
+{
+    let started = Instant::now();
+    let reader: Box<dyn Read + Send> = if let Some(path) = &config.static_data {
+        Box::new(File::open(path)?)
+    } else {
+        let body = fetch_siri(config)
+            .await?
+            .bytes_stream()
+            .map_err(io::Error::other);
+        Box::new(SyncIoBridge::new(StreamReader::new(body)))
+    };
+    metrics
+        .fetch_duration
+        .observe(started.elapsed().as_secs_f64());
+
+    let format = config.format;
+    let metrics = metrics.clone();
+    tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let mut reader = CountingReader {
+            inner: BufReader::with_capacity(256 * 1024, reader),
+            bytes: 0,
+        };
+        let parsed = parse(format, &mut reader)?;
+        metrics.fetch_bytes.observe(reader.bytes as f64);
+        metrics
+            .parse_duration
+            .observe(started.elapsed().as_secs_f64());
+        Ok(parsed)
+    })
+    .await?
+}
@@ -173,6 +173,7 @@ pub struct EstimatedCalls {
 pub struct EstimatedCall {
     pub aimed_arrival_time: Option<DateTime<FixedOffset>>,
     pub aimed_departure_time: Option<DateTime<FixedOffset>>,
+    pub arrival_platform_name: Option<StringValue>,
     pub arrival_status: Option<String>,
     pub arrival_stop_assignment: Option<StopAssignment>,
     /// This particular call/stop is cancelled, but not necessary the journey.
@@ -0,0 +1,235 @@
+// Upstream SIRI feeds, each fetched on its own schedule and merged into the same state
+use crate::cli::SharedOptions;
+use crate::entur_data::{self, Config, Format};
+use crate::ingest::{self, IngestFilter, Parsed};
+use crate::membased::{Journeys, JourneysBuilder};
+use crate::resilience::{CircuitBreaker, RetryPolicy};
+use crate::server::state::{AppState, fetch_with_retry};
+use anyhow::bail;
+use fxhash::FxHashSet;
+use reqwest::Client;
+use serde::Deserialize;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch::Receiver;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// The contents of the file passed to `--feeds`, in TOML.
+#[derive(Deserialize)]
+struct FeedsConfig {
+    feeds: Vec<FeedConfig>,
+}
+
+#[derive(Deserialize)]
+struct FeedConfig {
+    name: String,
+    /// An http(s) URL to poll, or the path to a file with static data
+    url: String,
+    #[serde(default)]
+    format: Format,
+    /// Fetch again every interval_seconds seconds. If left out, the feed is only fetched once.
+    interval_seconds: Option<u64>,
+    /// Generated on startup if left out, so that we receive the full dataset the first time
+    requestor_id: Option<String>,
+    /// Only keep journeys from these data sources, eg. VYG
+    data_sources: Option<Vec<String>>,
+}
+
+/// One upstream we fetch journeys from
+pub struct Feed {
+    pub name: Arc<str>,
+    config: Config,
+    interval: Option<Duration>,
+    filter: Arc<IngestFilter>,
+}
+
+/// The journeys from one successful fetch of a feed
+pub struct Batch {
+    pub feed: Arc<str>,
+    pub journeys: Journeys,
+}
+
+impl Feed {
+    pub fn new(
+        name: &str,
+        config: Config,
+        interval: Option<Duration>,
+        filter: IngestFilter,
+    ) -> Self {
+        Self {
+            name: Arc::from(name),
+            config,
+            interval,
+            filter: Arc::new(filter),
+        }
+    }
+
+    /// The single feed configured with --api-url or --static-data
+    pub fn from_options(
+        options: &SharedOptions,
+        fetch_interval_seconds: Option<u16>,
+        filter: &IngestFilter,
+        client: &Client,
+    ) -> Self {
+        let requestor_id = options
+            .requestor_id
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        Feed::new(
+            "default",
+            Config::new(
+                requestor_id,
+                options.api_url.clone(),
+                client.clone(),
+                options.static_data.clone(),
+                Format::SiriJson,
+            ),
+            fetch_interval_seconds.map(|seconds| Duration::from_secs(seconds as u64)),
+            filter.clone(),
+        )
+    }
+
+    /// Feeds from a TOML file, which all use `filter`, along with their own data sources
+    pub fn from_file(
+        path: &str,
+        filter: &IngestFilter,
+        client: &Client,
+    ) -> anyhow::Result<Vec<Self>> {
+        let config: FeedsConfig = toml::from_str(&fs::read_to_string(path)?)?;
+        if config.feeds.is_empty() {
+            bail!("No feeds in {path}");
+        }
+        let mut names = FxHashSet::default();
+        let mut feeds = Vec::with_capacity(config.feeds.len());
+        for feed in config.feeds {
+            if !names.insert(feed.name.clone()) {
+                bail!("The feed name {} is used more than once", feed.name);
+            }
+            if feed.interval_seconds == Some(0) {
+                bail!(
+                    "The feed {} must have an interval of at least 1 second",
+                    feed.name
+                );
+            }
+            let remote = feed.url.starts_with("http://") || feed.url.starts_with("https://");
+            let (api_url, static_data) = if remote {
+                (feed.url, None)
+            } else {
+                (String::new(), Some(feed.url))
+            };
+            let requestor_id = feed
+                .requestor_id
+                .unwrap_or_else(|| Uuid::new_v4().to_string());
+            let filter = match feed.data_sources {
+                Some(data_sources) => filter.clone().restrict_data_sources(data_sources),
+                None => filter.clone(),
+            };
+            feeds.push(Feed::new(
+                &feed.name,
+                Config::new(
+                    requestor_id,
+                    api_url,
+                    client.clone(),
+                    static_data,
+                    feed.format,
+                ),
+                feed.interval_seconds.map(Duration::from_secs),
+                filter,
+            ));
+        }
+        info!("Loaded {} feeds from {path}", feeds.len());
+        Ok(feeds)
+    }
+
+    /// We fetch again every interval
+    pub fn is_periodic(&self) -> bool {
+        self.interval.is_some()
+    }
+
+    /// Fetch once, and build the journeys that pass our filter as we parse them, against the
+    /// stops we have now
+    pub async fn fetch(&self, state: &AppState) -> anyhow::Result<Parsed> {
+        let stops = state.stops.load_full();
+        let stuck = state.stuck.clone();
+        let filter = self.filter.clone();
+        let feed = self.name.clone();
+        let parsed = entur_data::fetch_data(&self.config, &state.metrics, move |format, reader| {
+            let builder = JourneysBuilder::new(&stops, &stuck, feed);
+            match format {
+                Format::SiriJson => ingest::parse(reader, &filter, builder),
+            }
+        })
+        .await?;
+        state.metrics.filtered.inc_by(parsed.skipped as u64);
+        Ok(parsed)
+    }
+
+    /// Fetch right away, then every interval, and send what we get to `batches`. Each feed has
+    /// its own circuit breaker, so that one failing upstream doesn't hold back the others.
+    pub fn spawn(
+        self,
+        retry: RetryPolicy,
+        mut breaker: CircuitBreaker,
+        state: AppState,
+        batches: Sender<Batch>,
+        mut recv_shutdown: Receiver<bool>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let name = self.name.clone();
+            // When we only fetch once, keep trying for as long as it takes
+            let retry = if self.is_periodic() {
+                retry
+            } else {
+                RetryPolicy {
+                    attempts: u32::MAX,
+                    ..retry
+                }
+            };
+            // Not polled again when we only fetch once
+            let mut interval =
+                tokio::time::interval(self.interval.unwrap_or(Duration::from_secs(1)));
+            // Retries can take longer than the interval, don't make up for the ticks we missed then
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                if *recv_shutdown.borrow() {
+                    break;
+                }
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = recv_shutdown.changed() => break,
+                }
+                if !breaker.allow() {
+                    warn!("Not fetching {name} while the circuit breaker is open");
+                    continue;
+                }
+                let fetched = fetch_with_retry(&self, &retry, &state, &mut recv_shutdown).await;
+                match fetched {
+                    Ok(parsed) => {
+                        breaker.record_success();
+                        state.observe_breaker(&name, &breaker);
+                        // The receiver is gone when we're shutting down
+                        let batch = Batch {
+                            feed: name.clone(),
+                            journeys: parsed.journeys,
+                        };
+                        if batches.send(batch).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(reason) => {
+                        breaker.record_failure();
+                        state.observe_breaker(&name, &breaker);
+                        error!("Unable to fetch {name}: {reason:?}");
+                    }
+                }
+                if !self.is_periodic() {
+                    break;
+                }
+            }
+            info!("Stopped fetching {name}");
+        })
+    }
+}
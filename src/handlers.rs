@@ -1,71 +1,497 @@
 // HTTP request handlers
-use crate::api::{Healthy, JourneyDelay, TrainJourney, TrainsPage};
+use crate::api::v1::{
+    JourneyDelay, JourneyDetail, SourceQuality, StopArea, StopPlaceMatch, TrainJourney,
+    UnresolvedStops,
+};
+use crate::api::{
+    AtomFeed, FeedEntry, Healthy, JourneyPage, QualityPage, Readiness, SourceFreshness, TrainsPage,
+};
+use crate::membased::{Journey, ModeSummary};
+use crate::params::ListParams;
+use crate::resilience::FetchStatus;
 use crate::server::infra::WebappError;
 use crate::server::state::AppState;
 use axum::Json;
-use axum::extract::{Path, State};
-use axum::response::IntoResponse;
-use std::cmp::Reverse;
+use axum::extract::{Path, Query, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use serde::Deserialize;
+use std::sync::PoisonError;
+use std::sync::atomic::Ordering;
 use tracing::instrument;
+use utoipa::IntoParams;
 
 pub async fn root() -> impl IntoResponse {
     axum::response::Redirect::to("trains.html")
 }
 
+pub async fn quality_redirect() -> impl IntoResponse {
+    axum::response::Redirect::to("quality.html")
+}
+
+const TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+#[utoipa::path(
+    get,
+    path = "/stop/{stop_name}",
+    tag = "journeys",
+    params(("stop_name" = String, Path, description = "Name of a stop, as listed by /stops"), ListParams),
+    responses((
+        status = 200,
+        description = "Journeys that will visit the stop",
+        body = Vec<JourneyDelay>,
+        headers(("x-total-count" = usize, description = "Number of journeys before pagination"))
+    ))
+)]
 #[instrument(name = "by_stop_name", skip(state))]
 pub async fn by_stop_name(
     State(state): State<AppState>,
     Path(stop_name): Path<String>,
-) -> Result<Json<Vec<JourneyDelay>>, WebappError> {
-    let journeys = state.state.read().unwrap();
-    let journeys = journeys.by_visits(stop_name.as_str()).into_iter().cloned();
-    Ok(Json(journeys.map(|journey| journey.into()).collect()))
+    Query(params): Query<ListParams>,
+) -> Result<impl IntoResponse, WebappError> {
+    let journeys = state.state.load();
+    let journeys = params.select(journeys.by_visits(stop_name.as_str()));
+    let total = journeys.len();
+    let journeys: Vec<JourneyDelay> = params
+        .page(journeys)
+        .into_iter()
+        .map(JourneyDelay::from)
+        .collect();
+    Ok(([(TOTAL_COUNT, total)], Json(journeys)))
 }
 
-#[instrument(name = "train_journeys", skip_all)]
+#[utoipa::path(
+    get,
+    path = "/trains",
+    tag = "journeys",
+    params(ListParams),
+    responses((
+        status = 200,
+        description = "Train journeys currently in traffic",
+        body = Vec<TrainJourney>,
+        headers(("x-total-count" = usize, description = "Number of journeys before pagination"))
+    ))
+)]
+#[instrument(name = "train_journeys", skip(state, headers))]
 pub async fn train_journeys(
     State(state): State<AppState>,
-) -> Result<Json<Vec<TrainJourney>>, WebappError> {
-    let journeys = state.state.read().unwrap();
-    let mut train_journeys: Vec<TrainJourney> = journeys
-        .train_journeys()
+    Query(params): Query<ListParams>,
+    headers: HeaderMap,
+) -> Result<Response, WebappError> {
+    if params == ListParams::default()
+        && let Some(cached) = state
+            .responses
+            .load()
+            .as_ref()
+            .filter(|cached| cached.is_fresh())
+    {
+        let mut response = cached.trains.respond(&headers);
+        response
+            .headers_mut()
+            .insert(TOTAL_COUNT, cached.trains_total.into());
+        return Ok(response);
+    }
+    let journeys = state.state.load();
+    let journeys = params.select(journeys.train_journeys());
+    let total = journeys.len();
+    let train_journeys: Vec<TrainJourney> = params
+        .page(journeys)
         .into_iter()
-        .cloned()
-        .map(|tj| tj.into())
+        .map(TrainJourney::from)
         .collect();
-    train_journeys.sort_by_key(|tj| Reverse((tj.possibly_stuck, tj.delay_seconds)));
-    Ok(Json(train_journeys))
+    Ok(([(TOTAL_COUNT, total)], Json(train_journeys)).into_response())
 }
 
-#[instrument(name = "train_journeys_html", skip_all)]
+#[instrument(name = "train_journeys_html", skip(state, headers))]
 pub async fn train_journeys_html(
     State(state): State<AppState>,
-) -> Result<impl IntoResponse, WebappError> {
-    let journeys = state.state.read().unwrap();
-    let mut train_journeys: Vec<TrainJourney> = journeys
-        .train_journeys()
+    Query(params): Query<ListParams>,
+    headers: HeaderMap,
+) -> Result<Response, WebappError> {
+    if params == ListParams::default()
+        && let Some(cached) = state
+            .responses
+            .load()
+            .as_ref()
+            .filter(|cached| cached.is_fresh())
+    {
+        return Ok(cached.trains_html.respond(&headers));
+    }
+    let journeys = state.state.load();
+    let train_journeys: Vec<TrainJourney> = params
+        .select(journeys.train_journeys())
         .into_iter()
-        .cloned()
-        .map(|tj| tj.into())
+        .map(TrainJourney::from)
         .collect();
-    train_journeys.sort_by_key(|tj| Reverse((tj.possibly_stuck, tj.delay_seconds)));
-    Ok(TrainsPage::new(train_journeys, state.assets_path.clone()))
+    Ok(TrainsPage::new(train_journeys, params, state.assets_path.clone()).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/journeys/{id}",
+    tag = "journeys",
+    params(("id" = String, Path, description = "The vehicle journey id, eg. VYG:ServiceJourney:123")),
+    responses(
+        (status = 200, description = "The journey, its stops and any replacement services", body = JourneyDetail),
+        (status = 404, description = "Unknown journey, or it has expired")
+    )
+)]
+#[instrument(name = "journey", skip(state))]
+pub async fn journey(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response, WebappError> {
+    let journeys = state.state.load();
+    Ok(match journeys.get(id.as_str()) {
+        Some(journey) => Json(JourneyDetail::from(journey)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+#[instrument(name = "journey_html", skip(state))]
+pub async fn journey_html(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response, WebappError> {
+    let journeys = state.state.load();
+    Ok(match journeys.get(id.as_str()) {
+        Some(journey) => JourneyPage::new(JourneyDetail::from(journey), state.assets_path.clone())
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub struct FeedParams {
+    /// Only make entries for journeys delayed more than this many minutes
+    min_delay: Option<u32>,
+}
+
+fn feed_entries<'a>(
+    journeys: impl IntoIterator<Item = &'a Journey>,
+    params: &FeedParams,
+) -> Vec<FeedEntry> {
+    let min_delay_seconds = params.min_delay.unwrap_or(5) as i32 * 60;
+    journeys
+        .into_iter()
+        .flat_map(|journey| journey.feed_entries(min_delay_seconds))
+        .collect()
+}
+
+/// Everything but the unreserved characters of RFC 3986, for a single path segment
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// URNs may have colons too, like the LineRefs they're made of
+const URN: &AsciiSet = &PATH_SEGMENT.remove(b':');
+
+#[utoipa::path(
+    get,
+    path = "/trains.atom",
+    tag = "feeds",
+    params(FeedParams),
+    responses((status = 200, description = "Atom feed of disrupted trains", body = String, content_type = "application/atom+xml"))
+)]
+#[instrument(name = "trains_feed", skip(state))]
+pub async fn trains_feed(
+    State(state): State<AppState>,
+    Query(params): Query<FeedParams>,
+) -> Result<AtomFeed, WebappError> {
+    let journeys = state.state.load();
+    Ok(AtomFeed::new(
+        "urn:forsinka:trains".to_string(),
+        "Forsinkede og kansellerte tog".to_string(),
+        "trains.atom".to_string(),
+        feed_entries(journeys.train_journeys(), &params),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/stop/{stop_name}/feed.atom",
+    tag = "feeds",
+    params(("stop_name" = String, Path, description = "Name of a stop, as listed by /stops"), FeedParams),
+    responses((status = 200, description = "Atom feed of disrupted journeys that will visit the stop", body = String, content_type = "application/atom+xml"))
+)]
+#[instrument(name = "stop_feed", skip(state))]
+pub async fn stop_feed(
+    State(state): State<AppState>,
+    Path(stop_name): Path<String>,
+    Query(params): Query<FeedParams>,
+) -> Result<AtomFeed, WebappError> {
+    let journeys = state.state.load();
+    Ok(AtomFeed::new(
+        format!("urn:forsinka:stop:{}", utf8_percent_encode(&stop_name, URN)),
+        format!("Forsinkelser mot {stop_name}"),
+        // Relative to this feed, so that it works behind a proxy that serves us under a prefix
+        format!(
+            "../{}/feed.atom",
+            utf8_percent_encode(&stop_name, PATH_SEGMENT)
+        ),
+        feed_entries(journeys.by_visits(stop_name.as_str()), &params),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/line/{line}/feed.atom",
+    tag = "feeds",
+    params(("line" = String, Path, description = "Either the entire LineRef, or the last part of it, eg. R10"), FeedParams),
+    responses((status = 200, description = "Atom feed of disrupted journeys on the line", body = String, content_type = "application/atom+xml"))
+)]
+#[instrument(name = "line_feed", skip(state))]
+pub async fn line_feed(
+    State(state): State<AppState>,
+    Path(line): Path<String>,
+    Query(params): Query<FeedParams>,
+) -> Result<AtomFeed, WebappError> {
+    let journeys = state.state.load();
+    Ok(AtomFeed::new(
+        format!("urn:forsinka:line:{}", utf8_percent_encode(&line, URN)),
+        format!("Forsinkelser på {line}"),
+        format!("../{}/feed.atom", utf8_percent_encode(&line, PATH_SEGMENT)),
+        feed_entries(journeys.by_line(line.as_str()), &params),
+    ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/stops",
+    tag = "stops",
+    responses((status = 200, description = "All known stop names", body = Vec<String>))
+)]
 pub async fn stop_names(State(state): State<AppState>) -> Result<Json<Vec<String>>, WebappError> {
-    Ok(Json(state.stops.stop_names().collect()))
+    let stops = state.stops.load_full();
+    Ok(Json(stops.stop_names().collect()))
 }
 
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub struct SearchParams {
+    /// Part of a stop name, short name, public code or alternative name
+    q: String,
+    /// At most this many matches, defaults to 10
+    limit: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/stops/search",
+    tag = "stops",
+    params(SearchParams),
+    responses((status = 200, description = "Matching stop places, best matches first", body = Vec<StopPlaceMatch>))
+)]
+#[instrument(name = "search_stops", skip(state))]
+pub async fn search_stops(
+    State(state): State<AppState>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<Vec<StopPlaceMatch>>, WebappError> {
+    let limit = params.limit.unwrap_or(10).min(100);
+    let stops = state.stops.load_full();
+    Ok(Json(
+        stops
+            .search(params.q.as_str(), limit)
+            .into_iter()
+            .map(StopPlaceMatch::from)
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/stop-places/{id}",
+    tag = "stops",
+    params(("id" = String, Path, description = "A quay, stop place or parent station id, eg. NSR:StopPlace:337")),
+    responses(
+        (status = 200, description = "The stop, the quays below it and a summary of the journeys visiting them", body = StopArea),
+        (status = 404, description = "Unknown id")
+    )
+)]
+#[instrument(name = "stop_area", skip(state))]
+pub async fn stop_area(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response, WebappError> {
+    let stops = state.stops.load_full();
+    let Some(area) = stops.area(id.as_str()) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let journeys = state.state.load();
+    let mut summary = ModeSummary::default();
+    for journey in journeys.by_area(&area) {
+        summary.add(journey);
+    }
+    Ok(Json(StopArea::new(&area, summary)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/stop-places/{id}/journeys",
+    tag = "journeys",
+    params(("id" = String, Path, description = "A quay, stop place or parent station id, eg. NSR:StopPlace:337"), ListParams),
+    responses(
+        (
+            status = 200,
+            description = "Journeys that will visit any quay at or below the stop",
+            body = Vec<JourneyDelay>,
+            headers(("x-total-count" = usize, description = "Number of journeys before pagination"))
+        ),
+        (status = 404, description = "Unknown id")
+    )
+)]
+#[instrument(name = "by_stop_area", skip(state))]
+pub async fn by_stop_area(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<ListParams>,
+) -> Result<Response, WebappError> {
+    let stops = state.stops.load_full();
+    let Some(area) = stops.area(id.as_str()) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let journeys = state.state.load();
+    let journeys = params.select(journeys.by_area(&area));
+    let total = journeys.len();
+    let journeys: Vec<JourneyDelay> = params
+        .page(journeys)
+        .into_iter()
+        .map(JourneyDelay::from)
+        .collect();
+    Ok(([(TOTAL_COUNT, total)], Json(journeys)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/quality",
+    tag = "operations",
+    responses((status = 200, description = "Data quality per data source", body = Vec<SourceQuality>))
+)]
+#[instrument(name = "quality", skip(state))]
+pub async fn quality(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<SourceQuality>>, WebappError> {
+    let journeys = state.state.load();
+    Ok(Json(SourceQuality::report(&journeys)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/unresolved-stops",
+    tag = "operations",
+    responses((status = 200, description = "StopPointRefs that aren't in the stop registry", body = UnresolvedStops))
+)]
+#[instrument(name = "unresolved_stops", skip(state))]
+pub async fn unresolved_stops(
+    State(state): State<AppState>,
+) -> Result<Json<UnresolvedStops>, WebappError> {
+    let journeys = state.state.load();
+    Ok(Json(UnresolvedStops::from(journeys.unresolved_stops())))
+}
+
+#[instrument(name = "quality_html", skip(state))]
+pub async fn quality_html(State(state): State<AppState>) -> Result<QualityPage, WebappError> {
+    let journeys = state.state.load();
+    Ok(QualityPage::new(
+        SourceQuality::report(&journeys),
+        state.assets_path.clone(),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthy",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Data is being refreshed", body = Healthy),
+        (status = 500, description = "The circuit breaker of a feed is open, after too many failed fetches in a row", body = Healthy)
+    )
+)]
 pub async fn healthy(State(app_state): State<AppState>) -> Healthy {
-    let last_successful_sync = app_state.last_successful_sync.read().ok().map(|last| *last);
-    let next_sync_attempt = app_state.next_sync.read().ok().map(|now| *now);
-    let healthy = next_sync_attempt
-        .and_then(|now| last_successful_sync.map(|then| now - then < 10))
-        .unwrap_or(false);
+    let last_successful_sync = app_state.last_successful_sync.load(Ordering::Relaxed);
+    let next_sync_attempt = app_state.next_sync.load(Ordering::Relaxed);
+    let stops_from_cache = app_state.stops_from_cache.load(Ordering::Relaxed);
+
+    // Only ever held briefly to record an attempt, so even a poisoned status is worth reporting
+    let fetch_status = app_state
+        .fetch_status
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    let healthy = fetch_status.values().all(FetchStatus::healthy);
 
     Healthy {
-        last_successful_sync,
-        next_sync_attempt,
+        last_successful_sync: Some(last_successful_sync),
+        next_sync_attempt: Some(next_sync_attempt),
         healthy,
+        stops_from_cache,
+        fetch_status,
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/livez",
+    tag = "operations",
+    responses((status = 200, description = "The process is up", body = String))
+)]
+pub async fn livez() -> impl IntoResponse {
+    ([(CACHE_CONTROL, "no-store")], "ok")
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Loaded, with fresh data", body = Readiness),
+        (status = 503, description = "Still loading, or all data sources are stale", body = Readiness)
+    )
+)]
+pub async fn readyz(State(state): State<AppState>) -> Readiness {
+    let loaded = state.loaded.load(Ordering::Acquire);
+    let now = Utc::now().fixed_offset();
+    let mut sources: Vec<_> = state
+        .state
+        .load()
+        .last_update_by_data_source()
+        .into_iter()
+        .map(|(data_source, last)| {
+            let age = now - last;
+            SourceFreshness {
+                data_source: data_source.to_string(),
+                seconds_since_last_update: age.num_seconds(),
+                fresh: state.max_data_age.is_none_or(|max_age| age <= max_age),
+            }
+        })
+        .collect();
+    sources.sort_by(|a, b| a.data_source.cmp(&b.data_source));
+    Readiness {
+        loaded,
+        ready: loaded && sources.iter().any(|source| source.fresh),
+        sources,
     }
 }
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses((status = 200, description = "Prometheus metrics", body = String, content_type = "text/plain"))
+)]
+pub async fn metrics(State(state): State<AppState>) -> Result<impl IntoResponse, WebappError> {
+    let body = state.metrics.render()?;
+    Ok((
+        [
+            (CONTENT_TYPE, prometheus::TEXT_FORMAT),
+            (CACHE_CONTROL, "no-store"),
+        ],
+        body,
+    ))
+}
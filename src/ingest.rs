@@ -0,0 +1,594 @@
+// Filters that decide which journeys we keep, applied while parsing so that we never build the
+// journeys we don't want
+use crate::cli::IngestOptions;
+use crate::entur_siriformat::StringValue;
+use crate::membased::{Journeys, JourneysBuilder, Stops};
+use anyhow::{Context, bail};
+use fxhash::FxHashSet;
+use serde::Deserialize;
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde_json::value::RawValue;
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+
+/// An area on the map, journeys that call at a quay inside it are kept
+#[derive(Clone, Copy, Debug)]
+pub struct BoundingBox {
+    min_lat: f32,
+    min_lon: f32,
+    max_lat: f32,
+    max_lon: f32,
+}
+
+impl BoundingBox {
+    fn contains(&self, (lat, lon): (f32, f32)) -> bool {
+        (self.min_lat..=self.max_lat).contains(&lat) && (self.min_lon..=self.max_lon).contains(&lon)
+    }
+}
+
+/// `min_lat,min_lon,max_lat,max_lon`, eg. `59.0,10.0,60.5,11.5`
+impl FromStr for BoundingBox {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let corners = s
+            .split(',')
+            .map(|part| part.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid bounding box {s}"))?;
+        let [min_lat, min_lon, max_lat, max_lon] = corners[..] else {
+            bail!("Expected min_lat,min_lon,max_lat,max_lon, got {s}");
+        };
+        if min_lat > max_lat || min_lon > max_lon {
+            bail!("The minimum corner of {s} must come before the maximum corner");
+        }
+        Ok(Self {
+            min_lat,
+            min_lon,
+            max_lat,
+            max_lon,
+        })
+    }
+}
+
+/// Which journeys to keep. Every filter that is set must match, unset filters match everything.
+#[derive(Clone, Default, Debug)]
+pub struct IngestFilter {
+    /// eg. VYG
+    pub data_sources: Option<FxHashSet<String>>,
+    /// Either the entire LineRef or the last part of it, eg. R10
+    pub lines: Option<Vec<String>>,
+    /// eg. rail
+    pub modes: Option<FxHashSet<String>>,
+    pub bbox: Option<BoundingBox>,
+}
+
+/// Just the parts of a journey we need to filter it
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JourneyHeader {
+    data_source: String,
+    line_ref: StringValue,
+    vehicle_mode: Option<Vec<String>>,
+    recorded_calls: Option<RecordedCallRefs>,
+    estimated_calls: Option<EstimatedCallRefs>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RecordedCallRefs {
+    recorded_call: Vec<CallRef>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EstimatedCallRefs {
+    estimated_call: Vec<CallRef>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CallRef {
+    stop_point_ref: Option<StringValue>,
+}
+
+impl From<IngestOptions> for IngestFilter {
+    fn from(options: IngestOptions) -> Self {
+        Self {
+            data_sources: options
+                .ingest_data_sources
+                .map(|sources| sources.into_iter().collect()),
+            lines: options.ingest_lines,
+            modes: options
+                .ingest_modes
+                .map(|modes| modes.into_iter().collect()),
+            bbox: options.ingest_bbox,
+        }
+    }
+}
+
+impl IngestFilter {
+    pub fn is_empty(&self) -> bool {
+        self.data_sources.is_none()
+            && self.lines.is_none()
+            && self.modes.is_none()
+            && self.bbox.is_none()
+    }
+
+    /// Only keep journeys from `data_sources`, in addition to what this filter already requires
+    pub fn restrict_data_sources(mut self, data_sources: Vec<String>) -> Self {
+        let data_sources: FxHashSet<_> = data_sources.into_iter().collect();
+        self.data_sources = Some(match self.data_sources {
+            Some(ours) => ours.intersection(&data_sources).cloned().collect(),
+            None => data_sources,
+        });
+        self
+    }
+
+    fn accepts(&self, journey: &JourneyHeader, stops: &Stops) -> bool {
+        let data_source = self
+            .data_sources
+            .as_ref()
+            .is_none_or(|sources| sources.contains(&journey.data_source));
+        let line_ref = journey.line_ref.value.as_str();
+        let line = self.lines.as_ref().is_none_or(|lines| {
+            lines
+                .iter()
+                .any(|line| line_ref == line || line_ref.split(':').next_back() == Some(line))
+        });
+        // The same as Journey::mode
+        let mode = journey
+            .vehicle_mode
+            .as_ref()
+            .and_then(|modes| modes.first())
+            .map(|mode| mode.as_str())
+            .unwrap_or("unknown");
+        let mode = self.modes.as_ref().is_none_or(|modes| modes.contains(mode));
+        // Checked last, since it's the most work
+        data_source
+            && line
+            && mode
+            && self.bbox.is_none_or(|bbox| {
+                let recorded = journey
+                    .recorded_calls
+                    .iter()
+                    .flat_map(|calls| calls.recorded_call.iter());
+                let estimated = journey
+                    .estimated_calls
+                    .iter()
+                    .flat_map(|calls| calls.estimated_call.iter());
+                recorded
+                    .chain(estimated)
+                    .filter_map(|call| call.stop_point_ref.as_ref())
+                    .filter_map(|stop_point_ref| stops.coordinates(&stop_point_ref.value))
+                    .any(|coordinates| bbox.contains(coordinates))
+            })
+    }
+}
+
+/// The journeys we kept from a SIRI ET document
+pub struct Parsed {
+    pub journeys: Journeys,
+    /// Left out by the filter
+    pub skipped: usize,
+}
+
+/// Where the journeys are in a SIRI ET document. Arrays along the way are searched element by
+/// element.
+const JOURNEY_PATH: [&str; 5] = [
+    "Siri",
+    "ServiceDelivery",
+    "EstimatedTimetableDelivery",
+    "EstimatedJourneyVersionFrame",
+    "EstimatedVehicleJourney",
+];
+
+/// Parse the journeys in `reader` that `filter` accepts, one at a time, and hand them to `builder`
+/// as we go. The others are only parsed as far as needed to check the filter. We never hold more
+/// than one unparsed journey in memory, so `reader` can be the body of a response as it arrives.
+pub fn parse(
+    reader: impl Read,
+    filter: &IngestFilter,
+    mut builder: JourneysBuilder,
+) -> anyhow::Result<Parsed> {
+    let mut skipped = 0;
+    let mut sink = |raw: &RawValue| -> Result<(), serde_json::Error> {
+        if !filter.is_empty() {
+            let header: JourneyHeader = serde_json::from_str(raw.get())?;
+            if !filter.accepts(&header, builder.stops()) {
+                skipped += 1;
+                return Ok(());
+            }
+        }
+        builder.add(serde_json::from_str(raw.get())?);
+        Ok(())
+    };
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let mut reached = 0;
+    JourneyWalker {
+        path: &JOURNEY_PATH,
+        sink: &mut sink,
+        reached: &mut reached,
+    }
+    .deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    // An empty delivery is fine, but something else entirely, like an error message, is not
+    if reached < 2 {
+        let error: serde_json::Error = de::Error::missing_field("ServiceDelivery");
+        return Err(error.into());
+    }
+    Ok(Parsed {
+        journeys: builder.build(),
+        skipped,
+    })
+}
+
+/// Follows `path` through a SIRI ET document, and hands each journey to `sink` without parsing it
+struct JourneyWalker<'a, F> {
+    path: &'a [&'static str],
+    sink: &'a mut F,
+    /// How many keys of the path we've found
+    reached: &'a mut usize,
+}
+
+impl<'de, F> DeserializeSeed<'de> for JourneyWalker<'_, F>
+where
+    F: FnMut(&RawValue) -> Result<(), serde_json::Error>,
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, F> Visitor<'de> for JourneyWalker<'_, F>
+where
+    F: FnMut(&RawValue) -> Result<(), serde_json::Error>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a SIRI ET document with {}", self.path.join("."))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let JourneyWalker {
+            path,
+            sink,
+            reached,
+        } = self;
+        while let Some(key) = map.next_key::<String>()? {
+            if path.first() == Some(&key.as_str()) {
+                let depth = JOURNEY_PATH.len() - path.len() + 1;
+                *reached = depth.max(*reached);
+                map.next_value_seed(JourneyWalker {
+                    path: &path[1..],
+                    sink: &mut *sink,
+                    reached: &mut *reached,
+                })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let JourneyWalker {
+            path,
+            sink,
+            reached,
+        } = self;
+        if path.is_empty() {
+            while let Some(journey) = seq.next_element::<Box<RawValue>>()? {
+                sink(&journey).map_err(de::Error::custom)?;
+            }
+        } else {
+            while seq
+                .next_element_seed(JourneyWalker {
+                    path,
+                    sink: &mut *sink,
+                    reached: &mut *reached,
+                })?
+                .is_some()
+            {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::StopRow;
+    use crate::stuck::StuckConfig;
+    use serde_json::{Value, json};
+    use std::sync::Arc;
+
+    /// A journey that has left `first` and will call at `next`
+    fn journey(id: &str, line_ref: &str, mode: &str, first: &str, next: &str) -> Value {
+        json!({
+            "RecordedAtTime": "2026-10-18T10:00:00+02:00",
+            "DataSource": line_ref.split(':').next().unwrap(),
+            "LineRef": {"value": line_ref},
+            "DirectionRef": {"value": "0"},
+            "DatedVehicleJourneyRef": {"value": id},
+            "VehicleMode": [mode],
+            "RecordedCalls": {"RecordedCall": [{
+                "Order": 1,
+                "StopPointRef": {"value": first},
+                "StopPointName": [{"value": first}],
+                "AimedDepartureTime": "2026-10-18T09:50:00+02:00",
+                "ActualDepartureTime": "2026-10-18T09:52:00+02:00"
+            }]},
+            "EstimatedCalls": {"EstimatedCall": [{
+                "Order": 2,
+                "StopPointRef": {"value": next},
+                "StopPointName": [{"value": next}],
+                "AimedArrivalTime": "2026-10-18T10:10:00+02:00",
+                "ExpectedArrivalTime": "2026-10-18T10:12:00+02:00"
+            }]}
+        })
+    }
+
+    /// A SIRI ET document with a delivery for each of `deliveries`
+    fn document(deliveries: Vec<Vec<Value>>) -> String {
+        let deliveries: Vec<_> = deliveries
+            .into_iter()
+            .map(|journeys| {
+                json!({
+                    "version": "2.0",
+                    "ResponseTimestamp": "2026-10-18T10:00:01+02:00",
+                    "EstimatedJourneyVersionFrame": [{
+                        "RecordedAtTime": "2026-10-18T10:00:00+02:00",
+                        "EstimatedVehicleJourney": journeys
+                    }]
+                })
+            })
+            .collect();
+        json!({
+            "Siri": {
+                "version": "2.0",
+                "ServiceDelivery": {
+                    "ResponseTimestamp": "2026-10-18T10:00:01+02:00",
+                    "ProducerRef": {"value": "ENT"},
+                    "EstimatedTimetableDelivery": deliveries
+                }
+            }
+        })
+        .to_string()
+    }
+
+    fn parse_with(stops: &Stops, filter: &IngestFilter, document: &str) -> anyhow::Result<Parsed> {
+        let stuck = StuckConfig::default();
+        let builder = JourneysBuilder::new(stops, &stuck, Arc::from("test"));
+        parse(document.as_bytes(), filter, builder)
+    }
+
+    fn ids(parsed: &Parsed) -> Vec<&str> {
+        let mut ids: Vec<_> = parsed.journeys.iter().map(|journey| journey.id()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn every_journey_reaches_the_builder() {
+        let stops = Stops::new(Vec::new(), Vec::new());
+        let document = document(vec![
+            vec![
+                journey("1", "VYG:Line:R10", "rail", "Oslo S", "Lillestrøm"),
+                journey("2", "VYG:Line:L1", "rail", "Oslo S", "Skøyen"),
+            ],
+            vec![],
+            vec![journey("3", "RUT:Line:31", "bus", "Tonsenhagen", "Grorud")],
+        ]);
+        // Without a filter, and with one that accepts everything, which parses the header first
+        let everything = IngestFilter {
+            modes: Some(["rail", "bus"].map(String::from).into_iter().collect()),
+            ..IngestFilter::default()
+        };
+        for filter in [IngestFilter::default(), everything] {
+            let parsed = parse_with(&stops, &filter, &document).unwrap();
+            assert_eq!(ids(&parsed), ["1", "2", "3"]);
+            assert_eq!(parsed.skipped, 0);
+        }
+
+        let journey = parse_with(&stops, &IngestFilter::default(), &document)
+            .unwrap()
+            .journeys
+            .get("3")
+            .map(|journey| {
+                (
+                    journey.prev_stop_name().to_string(),
+                    journey.mode().to_string(),
+                )
+            });
+        assert_eq!(
+            journey,
+            Some(("Tonsenhagen".to_string(), "bus".to_string()))
+        );
+    }
+
+    #[test]
+    fn an_empty_delivery_is_fine() {
+        let stops = Stops::new(Vec::new(), Vec::new());
+        let parsed = parse_with(&stops, &IngestFilter::default(), &document(vec![])).unwrap();
+        assert_eq!(parsed.journeys.len(), 0);
+    }
+
+    #[test]
+    fn tolerates_unknown_and_reordered_fields() {
+        let stops = Stops::new(Vec::new(), Vec::new());
+        let mut first = journey("1", "VYG:Line:R10", "rail", "Oslo S", "Lillestrøm");
+        first["Occupancy"] = json!("seatsAvailable");
+        first["SomethingNew"] = json!({"EstimatedVehicleJourney": [1, 2, 3]});
+        let second = journey("2", "VYG:Line:L1", "rail", "Oslo S", "Skøyen");
+        // ServiceDelivery after the fields we don't know, and the journeys before the rest of the
+        // frame, with keys along the way that look like the path but aren't on it
+        let document = format!(
+            r#"{{
+                "Siri": {{
+                    "EstimatedVehicleJourney": [{{"bogus": true}}],
+                    "Unknown": {{"ServiceDelivery": null}},
+                    "ServiceDelivery": {{
+                        "EstimatedTimetableDelivery": [{{
+                            "EstimatedJourneyVersionFrame": [
+                                {{"EstimatedVehicleJourney": [{first}], "RecordedAtTime": "2026-10-18T10:00:00+02:00"}},
+                                {{"Unknown": [], "EstimatedVehicleJourney": [{second}]}}
+                            ],
+                            "version": "2.0"
+                        }}],
+                        "MoreDataAvailable": false,
+                        "ProducerRef": {{"value": "ENT"}}
+                    }},
+                    "version": "2.0"
+                }}
+            }}"#
+        );
+        let vyg = IngestFilter::default().restrict_data_sources(vec!["VYG".to_string()]);
+        for filter in [IngestFilter::default(), vyg] {
+            let parsed = parse_with(&stops, &filter, &document).unwrap();
+            assert_eq!(ids(&parsed), ["1", "2"]);
+        }
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let stops = Stops::new(Vec::new(), Vec::new());
+        let document = document(vec![vec![
+            journey("1", "VYG:Line:R10", "rail", "Oslo S", "Lillestrøm"),
+            journey("2", "VYG:Line:L1", "rail", "Oslo S", "Skøyen"),
+        ]]);
+        // In the middle of a journey, between them, and just before the end
+        let between = document.find(r#"},{"DataSource""#).unwrap() + 1;
+        for end in [document.len() / 3, between, document.len() - 1] {
+            let result = parse_with(&stops, &IngestFilter::default(), &document[..end]);
+            assert!(result.is_err(), "Parsed {}", &document[..end]);
+        }
+    }
+
+    #[test]
+    fn something_else_is_an_error() {
+        let stops = Stops::new(Vec::new(), Vec::new());
+        for document in [r#"{"error": "Too many requests"}"#, "[]", r#"{"Siri": {}}"#] {
+            assert!(parse_with(&stops, &IngestFilter::default(), document).is_err());
+        }
+    }
+
+    /// Quays with coordinates, the other stops in these tests aren't in the registry
+    fn stops_with_coordinates() -> Stops {
+        let quay = |name: &str, lat: f32, lon: f32| StopRow {
+            name: name.to_string(),
+            stop_point_ref: format!("NSR:Quay:{name}"),
+            stop_place_ref: format!("NSR:StopPlace:{name}"),
+            public_code: None,
+            lat: Some(lat.into()),
+            lon: Some(lon.into()),
+        };
+        Stops::new(
+            vec![
+                quay("Oslo S", 59.911, 10.753),
+                quay("Lillestrøm", 59.953, 11.045),
+                quay("Trondheim S", 63.436, 10.399),
+            ],
+            Vec::new(),
+        )
+    }
+
+    /// Parse one of each kind of journey with `filter`, and check that only `kept` reach Journeys
+    fn assert_keeps(filter: IngestFilter, kept: &[&str]) {
+        let stops = stops_with_coordinates();
+        let document = document(vec![vec![
+            journey(
+                "r10",
+                "VYG:Line:R10",
+                "rail",
+                "NSR:Quay:Oslo S",
+                "NSR:Quay:Lillestrøm",
+            ),
+            journey(
+                "f6",
+                "GOA:Line:F6",
+                "rail",
+                "NSR:Quay:Trondheim S",
+                "NSR:Quay:Bergen",
+            ),
+            journey(
+                "31",
+                "RUT:Line:31",
+                "bus",
+                "NSR:Quay:Tonsenhagen",
+                "NSR:Quay:Grorud",
+            ),
+        ]]);
+        let parsed = parse_with(&stops, &filter, &document).unwrap();
+        assert_eq!(ids(&parsed), kept);
+        assert_eq!(parsed.skipped, 3 - kept.len());
+    }
+
+    #[test]
+    fn filters_by_data_source() {
+        let filter = IngestFilter::default().restrict_data_sources(vec!["VYG".to_string()]);
+        assert_keeps(filter, &["r10"]);
+        // Restricting again only keeps what both allow
+        let filter = IngestFilter::default()
+            .restrict_data_sources(vec!["VYG".to_string(), "RUT".to_string()])
+            .restrict_data_sources(vec!["RUT".to_string(), "GOA".to_string()]);
+        assert_keeps(filter, &["31"]);
+    }
+
+    #[test]
+    fn filters_by_line() {
+        let filter = IngestFilter {
+            lines: Some(vec!["R10".to_string(), "RUT:Line:31".to_string()]),
+            ..IngestFilter::default()
+        };
+        assert_keeps(filter, &["31", "r10"]);
+        // Only the full LineRef or the last part of it
+        let filter = IngestFilter {
+            lines: Some(vec!["Line".to_string(), "F".to_string()]),
+            ..IngestFilter::default()
+        };
+        assert_keeps(filter, &[]);
+    }
+
+    #[test]
+    fn filters_by_mode() {
+        let filter = IngestFilter {
+            modes: Some(["bus".to_string()].into_iter().collect()),
+            ..IngestFilter::default()
+        };
+        assert_keeps(filter, &["31"]);
+    }
+
+    #[test]
+    fn filters_by_bbox() {
+        // Around Oslo, the bus has no coordinates since its stops aren't in the registry
+        let filter = IngestFilter {
+            bbox: Some("59.8,10.5,60.1,11.2".parse().unwrap()),
+            ..IngestFilter::default()
+        };
+        assert_keeps(filter, &["r10"]);
+        // Any call inside is enough
+        let filter = IngestFilter {
+            bbox: Some("63,10,64,11".parse().unwrap()),
+            ..IngestFilter::default()
+        };
+        assert_keeps(filter, &["f6"]);
+        assert!("60,10,59,11".parse::<BoundingBox>().is_err());
+    }
+
+    #[test]
+    fn every_filter_must_match() {
+        let filter = IngestFilter {
+            modes: Some(["rail".to_string()].into_iter().collect()),
+            lines: Some(vec!["R10".to_string(), "F6".to_string()]),
+            bbox: Some("59.8,10.5,60.1,11.2".parse().unwrap()),
+            ..IngestFilter::default()
+        };
+        assert_keeps(filter.clone(), &["r10"]);
+        assert_keeps(filter.restrict_data_sources(vec!["GOA".to_string()]), &[]);
+    }
+}
@@ -1,23 +1,48 @@
-use crate::cli::{Commands, Forsinka};
+use crate::alerts::Alerts;
+use crate::cli::{Commands, Forsinka, RetryOptions, ServeOptions};
+use crate::feeds::Feed;
+use crate::ingest::IngestFilter;
 use crate::membased::{Journeys, Stops};
+use crate::metrics::Metrics;
+use crate::resilience::{CircuitBreaker, RetryPolicy};
 use crate::server::infra;
-use crate::server::state::{self, AppState};
+use crate::server::state::{self, AppState, BackgroundJobs, StopRefresh};
+use crate::snapshot::SnapshotConfig;
+use crate::stuck::StuckConfig;
+use anyhow::bail;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use chrono::TimeDelta;
 use clap::Parser;
-use std::sync::{Arc, RwLock};
-use tracing::info;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::{filter::EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 use tracing_subscriber::{fmt, registry};
 
+mod alerts;
 mod api;
+mod bench;
 mod cli;
 mod db;
 mod entur_data;
 mod entur_siriformat;
+mod feeds;
 mod handlers;
+mod ingest;
 mod membased;
+mod metrics;
+mod params;
+mod resilience;
 mod routes;
 mod server;
+mod snapshot;
+mod stop_source;
+mod stuck;
+#[cfg(test)]
+mod testing;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -31,33 +56,95 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let args = Forsinka::try_parse()?;
-    let Commands::Serve {
+    match args.command {
+        Commands::Serve(options) => serve(*options).await,
+        Commands::Openapi => {
+            println!("{}", routes::openapi().to_pretty_json()?);
+            Ok(())
+        }
+        Commands::Bench(options) => bench::run(options),
+    }
+}
+
+async fn serve(options: ServeOptions) -> anyhow::Result<()> {
+    let ServeOptions {
         shared_options,
+        retry_options,
+        ingest_options,
         port,
         fetch_interval_seconds,
+        feeds,
+        stop_refresh_minutes,
+        snapshot_path,
+        snapshot_interval_minutes,
+        ready_max_age_seconds,
         assets_path,
-    } = args.command;
-
-    let (conn, data, entur_config) = state::initial_import(
-        shared_options.requestor_id,
-        shared_options.api_url,
-        shared_options.static_data,
-        &shared_options.db_url,
-        &shared_options.parquet_root,
-        shared_options.threads,
-        shared_options.memory_gb,
-    )
-    .await?;
-
-    let stops = db::read_stops(&conn)?;
-    let stops = Stops::new(stops);
-    let journeys = Journeys::new(&stops, data.journeys());
+        alert_rules,
+        stuck_rules,
+        log_unresolved_stops,
+    } = options;
+
+    let metrics = Arc::new(Metrics::new()?);
+    let RetryOptions {
+        fetch_attempts,
+        fetch_retry_base_ms,
+        fetch_retry_max_ms,
+        circuit_breaker_failures,
+        circuit_breaker_cooldown_seconds,
+    } = retry_options;
+    let retry = RetryPolicy {
+        attempts: fetch_attempts.max(1),
+        base_delay: Duration::from_millis(fetch_retry_base_ms),
+        max_delay: Duration::from_millis(fetch_retry_max_ms),
+    };
+    let breaker = || {
+        CircuitBreaker::new(
+            circuit_breaker_failures.max(1),
+            Duration::from_secs(circuit_breaker_cooldown_seconds),
+        )
+    };
+    let alerts = alert_rules.as_deref().map(Alerts::from_file).transpose()?;
+    let stuck = stuck_rules
+        .as_deref()
+        .map(StuckConfig::from_file)
+        .transpose()?
+        .unwrap_or_default();
+
+    let snapshot = snapshot_path.map(|path| SnapshotConfig {
+        path: PathBuf::from(path),
+        interval: Duration::from_secs(snapshot_interval_minutes as u64 * 60),
+    });
+
+    let filter = IngestFilter::from(ingest_options);
+    let client = state::http_client()?;
+    let feeds = match feeds {
+        Some(path) => Feed::from_file(&path, &filter, &client)?,
+        None => vec![Feed::from_options(
+            &shared_options,
+            fetch_interval_seconds,
+            &filter,
+            &client,
+        )],
+    };
 
+    // Serve right away from an empty state, with a loading page until the import is done
+    let empty_stops = Stops::new(Vec::new(), Vec::new());
     let app_state = AppState {
-        state: Arc::new(RwLock::new(journeys)),
-        last_successful_sync: Arc::new(RwLock::new(0)),
-        next_sync: Arc::new(RwLock::new(0)),
-        stops: Arc::new(stops),
+        state: Arc::new(ArcSwap::from_pointee(Journeys::default())),
+        responses: Arc::new(ArcSwapOption::empty()),
+        loaded: Arc::new(AtomicBool::new(false)),
+        max_data_age: feeds
+            .iter()
+            .any(Feed::is_periodic)
+            .then(|| TimeDelta::seconds(ready_max_age_seconds as i64)),
+        last_successful_sync: Arc::new(AtomicU32::new(0)),
+        stops_from_cache: Arc::new(AtomicBool::new(false)),
+        next_sync: Arc::new(AtomicU32::new(0)),
+        fetch_status: Arc::new(RwLock::new(Default::default())),
+        stops: Arc::new(ArcSwap::from_pointee(empty_stops)),
+        metrics: metrics.clone(),
+        stuck: Arc::new(stuck),
+        log_unresolved_stops,
         assets_path,
     };
 
@@ -67,19 +154,125 @@ async fn main() -> anyhow::Result<()> {
 
     let listener = tokio::net::TcpListener::bind(addr.as_str()).await?;
     let (send_shutdown, recv_shutdown) = tokio::sync::watch::channel(false);
-    let maybe_task = state::set_up_fetch_job(
-        fetch_interval_seconds,
+    let server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(infra::shutdown_signal(send_shutdown))
+            .await
+    });
+
+    let import = state::initial_import(shared_options, client).await?;
+    let stops = Arc::new(Stops::new(
+        db::read_stops(&import.db)?,
+        db::read_stop_places(&import.db)?,
+    ));
+    // The feeds locate journeys with these
+    app_state.stops.store(stops.clone());
+    let stuck = app_state.stuck.clone();
+
+    // The feeds fetch right away, even when we start from a snapshot, to catch up on what we missed
+    let (send_batches, mut batches) = tokio::sync::mpsc::channel(feeds.len());
+    let feed_tasks: Vec<_> = feeds
+        .into_iter()
+        .map(|feed| {
+            feed.spawn(
+                retry.clone(),
+                breaker(),
+                app_state.clone(),
+                send_batches.clone(),
+                recv_shutdown.clone(),
+            )
+        })
+        .collect();
+    // Only the feeds send, so that we notice when all of them are done
+    drop(send_batches);
+
+    let restored = snapshot
+        .as_ref()
+        .and_then(|config| match snapshot::load(&config.path) {
+            Ok(restored) => restored,
+            Err(reason) => {
+                warn!("Unable to load snapshot, fetching instead: {reason:?}");
+                None
+            }
+        })
+        .map(|restored| {
+            let mut journeys = Journeys::restore(
+                &stops,
+                &stuck,
+                restored
+                    .journeys
+                    .into_iter()
+                    .map(|journey| (Arc::from(journey.feed), journey.id, journey.journey)),
+            );
+            journeys.expire(state::expiry_cutoff());
+            (journeys, restored.last_successful_sync, restored.next_sync)
+        })
+        // A snapshot where everything has expired is no better than an empty state
+        .filter(|(journeys, _, _)| journeys.len() > 0);
+
+    let (mut journeys, last_successful_sync, next_sync) = match restored {
+        Some(restored) => {
+            info!("Serving {} journeys from the snapshot", restored.0.len());
+            restored
+        }
+        None => {
+            // Without any data to serve, wait for whichever feed delivers first. The others are
+            // merged in by the state job.
+            let Some(batch) = batches.recv().await else {
+                if *recv_shutdown.borrow() {
+                    server.await??;
+                    return Ok(());
+                }
+                bail!("None of the feeds delivered any journeys");
+            };
+            let journeys = batch.journeys;
+            if log_unresolved_stops {
+                state::log_unresolved_stops(journeys.unresolved_stops());
+            }
+            metrics.updated.inc_by(journeys.len() as u64);
+            info!("Received {} journeys from {}", journeys.len(), batch.feed);
+            (journeys, 0, 0)
+        }
+    };
+    journeys.link_replacements();
+    metrics.observe_state(&journeys);
+
+    state::publish(&app_state, journeys);
+    app_state
+        .last_successful_sync
+        .store(last_successful_sync, Ordering::Relaxed);
+    app_state.next_sync.store(next_sync, Ordering::Relaxed);
+    app_state
+        .stops_from_cache
+        .store(import.stops_from_cache, Ordering::Relaxed);
+    // Last, so that whoever sees that we're loaded also sees the journeys
+    app_state.loaded.store(true, Ordering::Release);
+    info!("Initial import done");
+    if let Some(alerts) = alerts.as_ref() {
+        alerts.deliver(alerts.evaluate(&app_state.state.load()));
+    }
+
+    let stop_refresh = stop_refresh_minutes.map(|minutes| StopRefresh {
+        interval: Duration::from_secs(minutes as u64 * 60),
+        source: import.stop_source,
+        db: Arc::new(Mutex::new(import.db)),
+    });
+    let task = state::set_up_state_job(
+        BackgroundJobs {
+            batches,
+            stop_refresh,
+            snapshot,
+        },
         recv_shutdown,
-        entur_config,
+        alerts,
         app_state,
     );
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(infra::shutdown_signal(send_shutdown))
-        .await?;
+    server.await??;
 
-    if let Some(task) = maybe_task {
-        task.await?;
+    task.await?;
+    for feed_task in feed_tasks {
+        feed_task.await?;
     }
 
     info!("Terminating");
@@ -1,9 +1,22 @@
-use crate::api::{JourneyDelay, TrainJourney};
-use crate::db::StopRow;
-use crate::entur_siriformat::{EstimatedCall, EstimatedVehicleJourney, RecordedCall};
+mod quality;
+mod search;
+mod source;
+
+use crate::api::FeedEntry;
+use crate::db::{StopPlaceRow, StopRow};
+use crate::entur_siriformat::EstimatedVehicleJourney;
+use crate::membased::quality::CallStop;
+pub use crate::membased::quality::{SourceQuality, UnresolvedStops};
+use crate::membased::search::StopIndex;
+pub use crate::membased::source::{JourneySource, SourceCall};
+use crate::stuck::{StuckConfig, StuckRule};
 use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
+use chrono_tz::Europe::Oslo;
 use fxhash::{FxHashMap, FxHashSet};
 use ordered_float::OrderedFloat;
+use std::borrow::Borrow;
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct JourneyId(String);
@@ -18,13 +31,108 @@ pub struct Stop {
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct StopPointRef(String);
 
+/// Lets us look up quays by `&str`, the hash and equality are those of the inner String
+impl Borrow<str> for StopPointRef {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A stop place from the national stop registry, which has one or more quays
+#[derive(Clone, Debug)]
+pub struct StopPlace {
+    pub id: String,
+    pub name: String,
+    pub short_name: Option<String>,
+    pub public_code: Option<String>,
+    pub transport_mode: Option<String>,
+    pub alternative_names: Vec<String>,
+    /// The multimodal parent station this stop place belongs to, if any
+    pub parent_ref: Option<String>,
+    pub lat: Option<OrderedFloat<f32>>,
+    pub lon: Option<OrderedFloat<f32>>,
+    /// The transport mode of this stop place and any stop places below it
+    pub modes: Vec<String>,
+}
+
+impl From<StopPlaceRow> for StopPlace {
+    fn from(row: StopPlaceRow) -> Self {
+        Self {
+            modes: row.transport_mode.iter().cloned().collect(),
+            id: row.id,
+            name: row.name,
+            short_name: row.short_name,
+            public_code: row.public_code,
+            transport_mode: row.transport_mode,
+            alternative_names: row.alternative_names,
+            parent_ref: row.parent_ref,
+            lat: row.lat,
+            lon: row.lon,
+        }
+    }
+}
+
+/// A quay, or platform, where vehicles stop. Its StopPointRef is the quay id.
+#[derive(Clone, Debug)]
+pub struct Quay {
+    pub id: String,
+    pub public_code: Option<String>,
+    pub stop_place_ref: String,
+}
+
+/// Which level of the stop hierarchy an id refers to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopLevel {
+    Quay,
+    StopPlace,
+    Parent,
+}
+
+/// A quay, stop place or parent station together with every quay below it
+pub struct StopArea<'a> {
+    pub id: String,
+    pub name: String,
+    pub level: StopLevel,
+    /// The stop place of a quay, or the parent station of a stop place
+    pub parent: Option<&'a StopPlace>,
+    /// The stop places below a parent station
+    pub children: Vec<&'a StopPlace>,
+    pub quays: Vec<&'a Quay>,
+    quay_refs: FxHashSet<StopPointRef>,
+}
+
 #[derive(Clone)]
 pub struct Stops {
     stops: FxHashMap<StopPointRef, Stop>,
+    quays: FxHashMap<StopPointRef, Quay>,
+    places: Vec<StopPlace>,
+    place_by_id: FxHashMap<String, usize>,
+    /// Stop place id to the quays at that stop place
+    quays_by_place: FxHashMap<String, Vec<StopPointRef>>,
+    /// Parent station id to the stop places below it
+    children: FxHashMap<String, Vec<usize>>,
+    index: StopIndex,
 }
 
 impl Stops {
-    pub fn new(stops: Vec<StopRow>) -> Self {
+    pub fn new(stops: Vec<StopRow>, places: Vec<StopPlaceRow>) -> Self {
+        let mut quays = FxHashMap::default();
+        let mut quays_by_place: FxHashMap<String, Vec<StopPointRef>> = FxHashMap::default();
+        for row in stops.iter() {
+            let id = StopPointRef(row.stop_point_ref.to_string());
+            quays_by_place
+                .entry(row.stop_place_ref.clone())
+                .or_default()
+                .push(id.clone());
+            quays.insert(
+                id,
+                Quay {
+                    id: row.stop_point_ref.clone(),
+                    public_code: row.public_code.clone(),
+                    stop_place_ref: row.stop_place_ref.clone(),
+                },
+            );
+        }
         let stops = stops
             .iter()
             .map(|row| {
@@ -39,127 +147,418 @@ impl Stops {
                 )
             })
             .collect();
-        Self { stops }
+
+        let mut places: Vec<StopPlace> = places.into_iter().map(StopPlace::from).collect();
+        let place_by_id: FxHashMap<_, _> = places
+            .iter()
+            .enumerate()
+            .map(|(i, place)| (place.id.clone(), i))
+            .collect();
+        let mut children: FxHashMap<String, Vec<usize>> = FxHashMap::default();
+        for (i, place) in places.iter().enumerate() {
+            if let Some(parent) = &place.parent_ref {
+                children.entry(parent.clone()).or_default().push(i);
+            }
+        }
+        // Parent stations usually have no transport mode of their own
+        for (parent, below) in children.iter() {
+            let mut modes: Vec<String> = below
+                .iter()
+                .flat_map(|i| places[*i].transport_mode.clone())
+                .collect();
+            if let Some(&i) = place_by_id.get(parent) {
+                modes.append(&mut places[i].modes);
+                modes.sort();
+                modes.dedup();
+                places[i].modes = modes;
+            }
+        }
+
+        let index = StopIndex::new(places.iter().enumerate().flat_map(|(i, place)| {
+            std::iter::once(place.name.as_str())
+                .chain(place.short_name.as_deref())
+                .chain(place.public_code.as_deref())
+                .chain(place.alternative_names.iter().map(|name| name.as_str()))
+                .map(move |name| (i, name))
+        }));
+        Self {
+            stops,
+            quays,
+            places,
+            place_by_id,
+            quays_by_place,
+            children,
+            index,
+        }
+    }
+
+    /// The number of quays
+    pub fn len(&self) -> usize {
+        self.stops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stops.is_empty()
     }
+
     pub fn stop_names(&self) -> impl Iterator<Item = String> {
         let refs: FxHashSet<_> = self.stops.values().map(|stop| &stop.name).collect();
         refs.into_iter().cloned()
     }
+
+    /// Find stop places by name, short name, public code or alternative names. Ignores case and
+    /// accents, and tolerates some typos in longer queries.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<&StopPlace> {
+        self.index
+            .search(query, limit)
+            .into_iter()
+            .map(|i| &self.places[i])
+            .collect()
+    }
+
+    /// The public code of a quay, which is usually the platform or track number
+    fn platform(&self, quay: &StopPointRef) -> Option<&str> {
+        self.quays.get(quay)?.public_code.as_deref()
+    }
+
+    fn place(&self, id: &str) -> Option<&StopPlace> {
+        self.place_by_id.get(id).map(|&i| &self.places[i])
+    }
+
+    /// Look up a quay id, eg. `NSR:Quay:1`, or a stop place id, eg. `NSR:StopPlace:337`. Stop
+    /// places that have other stop places below them are parent stations, and include their quays.
+    pub fn area(&self, id: &str) -> Option<StopArea<'_>> {
+        let quay_ref = StopPointRef(id.to_string());
+        if let Some(quay) = self.quays.get(&quay_ref) {
+            return Some(StopArea {
+                id: id.to_string(),
+                name: self.get(id).map(|stop| stop.name.clone())?,
+                level: StopLevel::Quay,
+                parent: self.place(&quay.stop_place_ref),
+                children: Vec::new(),
+                quays: vec![quay],
+                quay_refs: FxHashSet::from_iter([quay_ref]),
+            });
+        }
+
+        let place = self.place(id)?;
+        let children: Vec<_> = self
+            .children
+            .get(id)
+            .into_iter()
+            .flatten()
+            .map(|&i| &self.places[i])
+            .collect();
+        let quay_refs: FxHashSet<_> = std::iter::once(id)
+            .chain(children.iter().map(|child| child.id.as_str()))
+            .flat_map(|place| self.quays_by_place.get(place).into_iter().flatten())
+            .cloned()
+            .collect();
+        let mut quays: Vec<_> = quay_refs
+            .iter()
+            .filter_map(|quay| self.quays.get(quay))
+            .collect();
+        quays.sort_by(|a, b| a.id.cmp(&b.id));
+        Some(StopArea {
+            id: id.to_string(),
+            name: place.name.clone(),
+            level: if children.is_empty() {
+                StopLevel::StopPlace
+            } else {
+                StopLevel::Parent
+            },
+            parent: place.parent_ref.as_deref().and_then(|p| self.place(p)),
+            children,
+            quays,
+            quay_refs,
+        })
+    }
 }
 
 impl Stops {
-    fn get(&self, stop_point_ref: &StopPointRef) -> Option<&Stop> {
+    fn get(&self, stop_point_ref: &str) -> Option<&Stop> {
         self.stops.get(stop_point_ref)
     }
+
+    /// Latitude and longitude of a quay, when the stop registry has them
+    pub fn coordinates(&self, stop_point_ref: &str) -> Option<(f32, f32)> {
+        let stop = self.stops.get(stop_point_ref)?;
+        Some((stop.lat?.into_inner(), stop.lon?.into_inner()))
+    }
 }
 
+/// An upcoming call where the vehicle is expected at another quay than planned
 #[derive(Clone, PartialEq, Eq, Debug)]
-pub struct Journey {
-    last_update: DateTime<FixedOffset>,
-    journey_id: JourneyId,
-    data_source: String,
-    line_ref: String,
-    cancelled: bool,
-    finished: bool,
-    origin: Stop,
-    destination: Stop,
-    prev_stop: Stop,
-    next_stop: Option<Stop>,
-    prev_stop_planned_time: DateTime<FixedOffset>,
-    prev_stop_actual_time: DateTime<FixedOffset>,
-    next_stop_planned_time: Option<DateTime<FixedOffset>>,
-    to_visit: FxHashSet<String>,
+pub struct PlatformChange {
+    pub stop_name: String,
+    pub aimed_quay_ref: String,
+    pub expected_quay_ref: String,
+    pub aimed_platform: Option<String>,
+    pub expected_platform: Option<String>,
+}
+
+impl PlatformChange {
+    fn new(stops: &Stops, call: &SourceCall) -> Option<Self> {
+        // The platform name belongs to the same side of the call as the quay change
+        let (change, platform_name) = match (&call.arrival_quay_change, &call.departure_quay_change)
+        {
+            (Some(change), _) => (change, &call.arrival_platform_name),
+            (None, Some(change)) => (change, &call.departure_platform_name),
+            (None, None) => return None,
+        };
+        let aimed = StopPointRef(change.aimed_quay_ref.clone());
+        let expected = StopPointRef(change.expected_quay_ref.clone());
+        Some(Self {
+            stop_name: stop_with_fallback(stops, call)?.name,
+            aimed_platform: stops.platform(&aimed).map(|p| p.to_string()),
+            // The platform name is the one the vehicle is expected at, and may be more precise than
+            // the public code of the quay, eg. 2A rather than 2
+            expected_platform: platform_name
+                .clone()
+                .or_else(|| stops.platform(&expected).map(|p| p.to_string())),
+            aimed_quay_ref: aimed.0,
+            expected_quay_ref: expected.0,
+        })
+    }
 }
 
-impl TryFrom<&EstimatedCall> for Stop {
-    type Error = ();
+/// The delay we expect at an upcoming call, both the producer's estimate and our own
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DelayPrediction {
+    pub stop_name: String,
+    pub aimed_time: DateTime<FixedOffset>,
+    /// From the expected times the producer sent us
+    pub expected_delay_seconds: Option<i32>,
+    /// The current delay, reduced by the slack in the schedule up to this call
+    pub modelled_delay_seconds: i32,
+    /// The producer flagged the prediction as inaccurate, or of low quality
+    pub unreliable: bool,
+}
 
-    fn try_from(value: &EstimatedCall) -> Result<Self, Self::Error> {
-        let v = value
-            .stop_point_name
-            .as_ref()
-            .and_then(|seg| seg.first())
-            .map(|name| Stop {
-                name: name.value.clone(),
-                lat: None,
-                lon: None,
-            });
-        if let Some(stop) = v {
-            Ok(stop)
-        } else {
-            Err(())
+impl DelayPrediction {
+    /// The producer's estimate when we trust it, otherwise our own
+    pub fn delay_seconds(&self) -> i32 {
+        match self.expected_delay_seconds {
+            Some(expected) if !self.unreliable => expected,
+            _ => self.modelled_delay_seconds,
         }
     }
+
+    /// `delay_seconds` is the producer's estimate rather than our own
+    pub fn by_producer(&self) -> bool {
+        self.expected_delay_seconds.is_some() && !self.unreliable
+    }
+
+    /// The producer expects a delay of more than a minute
+    pub fn expected_delayed(&self) -> bool {
+        self.expected_delay_seconds
+            .is_some_and(|expected| expected > 60)
+    }
 }
 
-impl TryFrom<&RecordedCall> for Stop {
-    type Error = ();
+/// Predict the delay at each of `calls`, given the delay and planned departure at the previous stop.
+///
+/// Our own model assumes that a delayed vehicle recovers time wherever the schedule has slack: by
+/// dwelling shorter than planned at stops, and by running a bit faster than the planned running time.
+fn predict_delays(
+    stops: &Stops,
+    calls: &[&SourceCall],
+    current_delay: TimeDelta,
+    prev_departure: DateTime<FixedOffset>,
+    journey_inaccurate: bool,
+) -> Vec<DelayPrediction> {
+    // The shortest dwell we expect at a stop, the rest of a planned dwell can be recovered
+    let min_dwell = TimeDelta::seconds(30);
+    // How much of the planned running time can be recovered, in percent
+    let running_slack_percent = 5;
 
-    fn try_from(value: &RecordedCall) -> Result<Self, Self::Error> {
-        let v = value
-            .stop_point_name
-            .as_ref()
-            .and_then(|seg| seg.first())
-            .map(|name| Stop {
-                name: name.value.clone(),
-                lat: None,
-                lon: None,
-            });
-        if let Some(stop) = v {
-            Ok(stop)
+    let mut predictions = Vec::with_capacity(calls.len());
+    let mut slack = TimeDelta::zero();
+    let mut last_departure = prev_departure;
+    for call in calls {
+        let Some(aimed_time) = call.aimed_arrival_time.or(call.aimed_departure_time) else {
+            continue;
+        };
+        slack += (aimed_time - last_departure) * running_slack_percent / 100;
+        let modelled = (current_delay - slack).max(TimeDelta::zero());
+        if let Some(departure) = call.aimed_departure_time {
+            slack += (departure - aimed_time - min_dwell).max(TimeDelta::zero());
+            last_departure = departure;
         } else {
-            Err(())
+            last_departure = aimed_time;
         }
+
+        let expected = call
+            .expected_arrival_time
+            .zip(call.aimed_arrival_time)
+            .or(call.expected_departure_time.zip(call.aimed_departure_time))
+            .map(|(expected, aimed)| (expected - aimed).num_seconds() as i32);
+        let Some(stop) = stop_with_fallback(stops, call) else {
+            continue;
+        };
+        predictions.push(DelayPrediction {
+            stop_name: stop.name,
+            aimed_time,
+            expected_delay_seconds: expected,
+            modelled_delay_seconds: modelled.num_seconds() as i32,
+            unreliable: journey_inaccurate || call.prediction_inaccurate || call.low_quality,
+        });
     }
+    predictions
 }
 
-impl TryFrom<&RecordedCall> for StopPointRef {
-    type Error = ();
+/// Refers to another journey, eg. the extra journey that replaces a cancelled one
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct JourneyLink {
+    pub id: String,
+    /// As in `Journey::describe_line`
+    pub line: String,
+    pub mode: String,
+}
 
-    fn try_from(value: &RecordedCall) -> Result<Self, Self::Error> {
-        if let Some(stop_point_ref) = &value.stop_point_ref {
-            Ok(StopPointRef(stop_point_ref.value.clone()))
-        } else {
-            Err(())
+impl From<&Journey> for JourneyLink {
+    fn from(value: &Journey) -> Self {
+        Self {
+            id: value.journey_id.0.clone(),
+            line: value.describe_line(),
+            mode: value.mode.clone(),
         }
     }
 }
 
-impl TryFrom<&EstimatedCall> for StopPointRef {
-    type Error = ();
+/// Why a journey that should have reached its next stop by now hasn't
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StuckKind {
+    /// We have recent data, so the vehicle itself is probably stuck
+    Vehicle,
+    /// The producer hasn't sent us anything for a while
+    DataStale,
+    /// The producer doesn't track the vehicle in real time
+    NotMonitored,
+}
 
-    fn try_from(value: &EstimatedCall) -> Result<Self, Self::Error> {
-        if let Some(stop_point_ref) = &value.stop_point_ref {
-            Ok(StopPointRef(stop_point_ref.value.clone()))
-        } else {
-            Err(())
+pub struct StuckStatus {
+    pub kind: StuckKind,
+    /// When the journey should have reached its next stop, with some cushion
+    pub since: DateTime<FixedOffset>,
+    /// When we checked
+    at: DateTime<FixedOffset>,
+}
+
+impl StuckStatus {
+    /// Explains the status of `journey`, in Norwegian, eg. for the HTML pages
+    pub fn reason(&self, journey: &Journey) -> String {
+        let expected = format!(
+            "Skulle vært på {} {}",
+            journey.next_stop_name().unwrap_or_default(),
+            journey
+                .next_stop_planned_time
+                .map(|next| next.with_timezone(&Oslo).format("%H:%M").to_string())
+                .unwrap_or_default()
+        );
+        match self.kind {
+            StuckKind::NotMonitored => format!("{expected}, men følges ikke i sanntid"),
+            StuckKind::DataStale => format!(
+                "{expected}, men vi har ikke fått data på {} min",
+                (self.at - journey.last_update).num_minutes()
+            ),
+            StuckKind::Vehicle => format!(
+                "{expected}, men var sist på {} for {} min siden",
+                journey.prev_stop.name,
+                (self.at - journey.prev_stop_actual_time).num_minutes()
+            ),
         }
     }
 }
 
-fn stop_with_fallback<C: TryInto<Stop> + TryInto<StopPointRef> + Copy>(
-    stops: &Stops,
-    call: C,
-) -> Option<Stop> {
-    let id: StopPointRef = call.try_into().ok()?;
-    let primary = stops.get(&id).cloned();
-    primary.or_else(|| call.try_into().ok())
+#[derive(Clone, Debug)]
+pub struct Journey {
+    last_update: DateTime<FixedOffset>,
+    journey_id: JourneyId,
+    data_source: String,
+    line_ref: String,
+    mode: String,
+    cancelled: bool,
+    origin: Stop,
+    destination: Stop,
+    prev_stop: Stop,
+    next_stop: Option<Stop>,
+    prev_stop_planned_time: DateTime<FixedOffset>,
+    prev_stop_actual_time: DateTime<FixedOffset>,
+    next_stop_planned_time: Option<DateTime<FixedOffset>>,
+    to_visit: FxHashSet<String>,
+    to_visit_quays: FxHashSet<StopPointRef>,
+    platform_changes: Vec<PlatformChange>,
+    /// Stops the journey skips, although the journey itself isn't cancelled
+    cancelled_stops: Vec<String>,
+    /// The stop the journey terminates at, when the calls after it are cancelled
+    short_turned_at: Option<String>,
+    /// All stops in order, including cancelled ones
+    planned_stops: Vec<String>,
+    planned_start: DateTime<FixedOffset>,
+    planned_end: DateTime<FixedOffset>,
+    /// Not in the original plan, usually a replacement for some other journey (eg. buss for tog)
+    extra: bool,
+    /// Extra journeys that replace this one, when it is cancelled
+    replaced_by: Vec<JourneyLink>,
+    /// Cancelled journeys this extra journey replaces
+    replaces: Vec<JourneyLink>,
+    /// Delay predictions for the upcoming calls that aren't cancelled
+    predictions: Vec<DelayPrediction>,
+    /// The producer tracks the vehicle in real time
+    monitored: bool,
+    stuck_rule: StuckRule,
+    /// What we received, so that we can resolve the stops again when the stop registry changes
+    source: Arc<JourneySource>,
+    /// The name of the feed we received it from
+    feed: Arc<str>,
+}
+
+/// The stop from the stop registry, or named after the StopPointName we received if it isn't there.
+/// Calls without a StopPointRef are left out.
+fn stop_with_fallback(stops: &Stops, call: &SourceCall) -> Option<Stop> {
+    let id = call.stop_point_ref.as_deref()?;
+    stops.get(id).cloned().or_else(|| {
+        call.stop_point_name.as_ref().map(|name| Stop {
+            name: name.clone(),
+            lat: None,
+            lon: None,
+        })
+    })
 }
 
 impl Journey {
-    fn new(stops: &Stops, journey_id: JourneyId, journey: EstimatedVehicleJourney) -> Option<Self> {
+    fn new(
+        stops: &Stops,
+        stuck: &StuckConfig,
+        journey_id: JourneyId,
+        source: Arc<JourneySource>,
+        feed: Arc<str>,
+    ) -> Option<Self> {
+        let journey = source.as_ref();
         let last_update = journey.recorded_at_time;
-        // This throws out journeys that haven't started, which is okay for us.
-        let recorded = journey.recorded_calls?.recorded_call;
-        let estimated = journey
-            .estimated_calls
-            .map(|ec| ec.estimated_call)
-            .unwrap_or_default();
-        let finished = estimated.is_empty();
-        let data_source = journey.data_source;
-        let first_recorded = recorded.first()?;
-
-        let prev = recorded.last()?;
+        let recorded = journey.recorded.as_slice();
+        let estimated = journey.estimated.as_slice();
+        let data_source = journey.data_source.clone();
+        let cancelled = journey.cancelled;
+        // When the whole journey is cancelled, we keep its calls so that it still shows up at the
+        // stops it was supposed to visit. Otherwise, cancelled calls are stops it will skip.
+        let skipped = |call: &SourceCall| !cancelled && call.cancelled;
+        // This throws out journeys that haven't started, which is okay for us, except for
+        // cancelled and extra journeys, which we link to each other. Until they start, their
+        // first stop is both the previous and the next one.
+        let first = match recorded.first() {
+            Some(first) => first,
+            None if cancelled || journey.extra => estimated.first()?,
+            None => return None,
+        };
+
+        let prev = recorded
+            .iter()
+            .rev()
+            .find(|rc| !skipped(rc))
+            .or(recorded.last())
+            .unwrap_or(first);
         // This throws out the whole journey if we don't have any actual or planned times for the previous stop
         let prev_stop_planned_time = prev.aimed_arrival_time.or(prev.aimed_departure_time)?;
         let prev_stop_actual_time = prev
@@ -170,7 +569,8 @@ impl Journey {
         let prev_stop: Stop = stop_with_fallback(stops, prev)?;
 
         let (next_stop, next_stop_planned_time) = estimated
-            .first()
+            .iter()
+            .find(|ec| !skipped(ec))
             .and_then(|first_estimated| {
                 Some((
                     stop_with_fallback(stops, first_estimated)?,
@@ -181,7 +581,7 @@ impl Journey {
             })
             .unzip();
 
-        let origin = stop_with_fallback(stops, first_recorded)?;
+        let origin = stop_with_fallback(stops, first)?;
 
         let destination = estimated
             .last()
@@ -192,20 +592,90 @@ impl Journey {
                     .and_then(|dest| stop_with_fallback(stops, dest))
             })?;
 
+        let cancelled_stops: Vec<String> = recorded
+            .iter()
+            .filter(|rc| skipped(rc))
+            .filter_map(|rc| stop_with_fallback(stops, rc))
+            .chain(
+                estimated
+                    .iter()
+                    .filter(|ec| skipped(ec))
+                    .filter_map(|ec| stop_with_fallback(stops, ec)),
+            )
+            .map(|stop| stop.name)
+            .collect();
+        // The journey is short-turned when the calls at the end of the journey are cancelled, so
+        // that it terminates at the last call that isn't.
+        let ends_skipped = match estimated.last() {
+            Some(last) => skipped(last),
+            None => recorded.last().is_some_and(skipped),
+        };
+        let short_turned_at = if ends_skipped {
+            estimated
+                .iter()
+                .rev()
+                .find(|ec| !skipped(ec))
+                .and_then(|ec| stop_with_fallback(stops, ec))
+                .or_else(|| Some(prev_stop.clone()))
+                .map(|stop| stop.name)
+        } else {
+            None
+        };
+
+        let planned_stops = recorded
+            .iter()
+            .filter_map(|rc| stop_with_fallback(stops, rc))
+            .chain(
+                estimated
+                    .iter()
+                    .filter_map(|ec| stop_with_fallback(stops, ec)),
+            )
+            .map(|stop| stop.name)
+            .collect();
+        let planned_start = first
+            .aimed_departure_time
+            .or(first.aimed_arrival_time)
+            .unwrap_or(prev_stop_planned_time);
+        let planned_end = estimated
+            .last()
+            .and_then(|ec| ec.aimed_arrival_time.or(ec.aimed_departure_time))
+            .unwrap_or(prev_stop_planned_time);
+
+        let estimated: Vec<_> = estimated.iter().filter(|ec| !skipped(ec)).collect();
+        let predictions = predict_delays(
+            stops,
+            &estimated,
+            prev_stop_actual_time - prev_stop_planned_time,
+            prev.aimed_departure_time.unwrap_or(prev_stop_planned_time),
+            journey.prediction_inaccurate,
+        );
+        let platform_changes = estimated
+            .iter()
+            .filter_map(|est| PlatformChange::new(stops, est))
+            .collect();
+        let to_visit_quays = estimated
+            .iter()
+            .filter_map(|est| est.stop_point_ref.clone().map(StopPointRef))
+            .collect();
         // This throws out only stops we can't find, not the actual journey
         let to_visit: FxHashSet<_> = estimated
             .into_iter()
-            .filter_map(|est| stop_with_fallback(stops, &est).map(|stop| stop.name))
+            .filter_map(|est| stop_with_fallback(stops, est).map(|stop| stop.name))
             .collect();
-        let line_ref = journey.line_ref.value;
+        let line_ref = journey.line_ref.clone();
+        let mode = journey
+            .mode
+            .clone()
+            .unwrap_or_else(|| "unknown".to_string());
+        let stuck_rule = stuck.rule_for(&mode, &data_source);
 
         Some(Self {
             last_update,
             journey_id,
             data_source,
             line_ref,
-            cancelled: journey.cancellation.unwrap_or(false),
-            finished,
+            mode,
+            cancelled,
             origin,
             destination,
             prev_stop,
@@ -214,36 +684,358 @@ impl Journey {
             prev_stop_actual_time,
             next_stop_planned_time,
             to_visit,
+            to_visit_quays,
+            platform_changes,
+            cancelled_stops,
+            short_turned_at,
+            planned_stops,
+            planned_start,
+            planned_end,
+            extra: journey.extra,
+            replaced_by: Vec::new(),
+            replaces: Vec::new(),
+            predictions,
+            monitored: journey.monitored,
+            stuck_rule,
+            source,
+            feed,
         })
     }
 
-    fn recorded_delay_seconds(&self) -> i32 {
+    /// Build the journey again from what we received, with a new stop registry
+    fn resolve(&self, stops: &Stops, stuck: &StuckConfig) -> Option<Self> {
+        Journey::new(
+            stops,
+            stuck,
+            self.journey_id.clone(),
+            self.source.clone(),
+            self.feed.clone(),
+        )
+    }
+
+    pub fn id(&self) -> &str {
+        &self.journey_id.0
+    }
+
+    pub fn data_source(&self) -> &str {
+        &self.data_source
+    }
+
+    pub fn feed(&self) -> &str {
+        &self.feed
+    }
+
+    pub fn line_ref(&self) -> &str {
+        &self.line_ref
+    }
+
+    pub fn prev_stop_name(&self) -> &str {
+        &self.prev_stop.name
+    }
+
+    pub fn prev_stop_planned_time(&self) -> DateTime<FixedOffset> {
+        self.prev_stop_planned_time
+    }
+
+    pub fn prev_stop_actual_time(&self) -> DateTime<FixedOffset> {
+        self.prev_stop_actual_time
+    }
+
+    pub fn next_stop_name(&self) -> Option<&str> {
+        self.next_stop.as_ref().map(|stop| stop.name.as_str())
+    }
+
+    pub fn next_stop_planned_time(&self) -> Option<DateTime<FixedOffset>> {
+        self.next_stop_planned_time
+    }
+
+    /// `line` can be either the entire LineRef, or the last part of it, eg. R10
+    pub fn on_line(&self, line: &str) -> bool {
+        self.line_ref == line || self.line_ref.split(':').next_back() == Some(line)
+    }
+
+    pub fn mode(&self) -> &str {
+        &self.mode
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Some of the calls are cancelled, but not the whole journey
+    pub fn is_partially_cancelled(&self) -> bool {
+        !self.cancelled_stops.is_empty()
+    }
+
+    pub fn cancelled_stops(&self) -> &[String] {
+        &self.cancelled_stops
+    }
+
+    pub fn short_turned_at(&self) -> Option<&str> {
+        self.short_turned_at.as_deref()
+    }
+
+    /// Delay predictions for the upcoming calls, in order
+    pub fn predictions(&self) -> &[DelayPrediction] {
+        &self.predictions
+    }
+
+    pub fn next_prediction(&self) -> Option<&DelayPrediction> {
+        self.predictions.first()
+    }
+
+    /// All stops in order, including the cancelled ones
+    pub fn planned_stops(&self) -> &[String] {
+        &self.planned_stops
+    }
+
+    /// The journey has visited at least one stop
+    pub fn departed(&self) -> bool {
+        self.source.started()
+    }
+
+    pub fn is_extra(&self) -> bool {
+        self.extra
+    }
+
+    pub fn replaced_by(&self) -> &[JourneyLink] {
+        &self.replaced_by
+    }
+
+    pub fn replaces(&self) -> &[JourneyLink] {
+        &self.replaces
+    }
+
+    /// Whether this extra journey looks like a replacement for `cancelled`. They must overlap in
+    /// time, and this journey must visit two of the cancelled stops, or one if the line is the same.
+    fn could_replace(&self, cancelled: &Journey) -> bool {
+        let affected = if cancelled.cancelled {
+            &cancelled.planned_stops
+        } else {
+            &cancelled.cancelled_stops
+        };
+        let shared = self
+            .planned_stops
+            .iter()
+            .filter(|stop| affected.contains(stop))
+            .count();
+        let slack = TimeDelta::hours(1);
+        let overlaps = self.planned_start <= cancelled.planned_end + slack
+            && cancelled.planned_start - slack <= self.planned_end;
+        overlaps && (shared >= 2 || (shared >= 1 && self.short_line() == cancelled.short_line()))
+    }
+
+    /// Upcoming calls where the vehicle is expected at another quay than planned
+    pub fn platform_changes(&self) -> &[PlatformChange] {
+        &self.platform_changes
+    }
+
+    pub fn recorded_delay_seconds(&self) -> i32 {
         (self.prev_stop_actual_time - self.prev_stop_planned_time).as_seconds_f32() as i32
     }
 
-    fn possibly_stuck(&self) -> bool {
-        if let Some(next) = self.next_stop_planned_time {
-            let planned_travel_time = next - self.prev_stop_planned_time;
-            let cushion = planned_travel_time + TimeDelta::minutes(8);
-            let cutoff = self.prev_stop_planned_time + cushion;
-            Utc::now() > cutoff
+    /// The journey should have reached its next stop a while ago, whether the vehicle is stuck or
+    /// we lack recent data for it. This is what `possibly_stuck` means in /api/v1.
+    pub fn overdue(&self) -> bool {
+        self.stuck_status().is_some()
+    }
+
+    /// The vehicle should have reached its next stop a while ago, according to recent data
+    pub fn possibly_stuck(&self) -> bool {
+        self.stuck_status()
+            .is_some_and(|status| status.kind == StuckKind::Vehicle)
+    }
+
+    /// The journey should have reached its next stop a while ago, but we can't tell whether it
+    /// has, because the producer doesn't send us recent data for it
+    pub fn data_stale(&self) -> bool {
+        self.stuck_status()
+            .is_some_and(|status| status.kind != StuckKind::Vehicle)
+    }
+
+    /// Set when the journey should have reached its next stop, with some cushion, and hasn't.
+    pub fn stuck_status(&self) -> Option<StuckStatus> {
+        // A cancelled journey isn't expected anywhere
+        if self.cancelled {
+            return None;
+        }
+        // At last stop if there is no next stop
+        let next = self.next_stop_planned_time?;
+        let since = next + self.stuck_rule.cushion;
+        let now = Utc::now().fixed_offset();
+        if now <= since {
+            return None;
+        }
+
+        let kind = if !self.monitored {
+            StuckKind::NotMonitored
+        } else if now - self.last_update > self.stuck_rule.stale_after {
+            StuckKind::DataStale
         } else {
-            // At last stop
-            false
+            StuckKind::Vehicle
+        };
+        Some(StuckStatus {
+            kind,
+            since,
+            at: now,
+        })
+    }
+
+    /// The last part of the LineRef, eg. R10
+    fn short_line(&self) -> &str {
+        self.line_ref.split(':').next_back().unwrap()
+    }
+
+    /// Short line name, origin and destination, eg. `R10: Drammen to Lillehammer`
+    pub fn describe_line(&self) -> String {
+        format!(
+            "{}: {} to {}",
+            self.short_line(),
+            self.origin.name.trim_end_matches(" stasjon"),
+            self.destination.name.trim_end_matches(" stasjon")
+        )
+    }
+
+    /// Feed entries for the ways this journey is disrupted. The entry ids are derived from the
+    /// journey id and the kind of disruption, so they stay the same across fetches.
+    pub fn feed_entries(&self, min_delay_seconds: i32) -> Vec<FeedEntry> {
+        let line = self.describe_line();
+        let summary = format!(
+            "Forrige stopp {} planlagt {}, faktisk {}.{}",
+            self.prev_stop.name,
+            self.prev_stop_planned_time
+                .with_timezone(&Oslo)
+                .format("%H:%M"),
+            self.prev_stop_actual_time
+                .with_timezone(&Oslo)
+                .format("%H:%M"),
+            self.next_stop
+                .as_ref()
+                .map(|next| format!(" Neste stopp {}.", next.name))
+                .unwrap_or_default()
+        );
+        let entry = |kind: &str, title: String, updated| FeedEntry {
+            id: format!("urn:forsinka:{}:{kind}", self.journey_id.0),
+            title,
+            summary: summary.clone(),
+            updated,
+        };
+
+        let mut entries = Vec::new();
+        if self.cancelled {
+            entries.push(entry(
+                "cancelled",
+                format!("{line} er kansellert"),
+                self.last_update,
+            ));
+            return entries;
+        }
+        if let Some(stop) = &self.short_turned_at {
+            entries.push(entry(
+                "short-turned",
+                format!("{line} snur på {stop}"),
+                self.last_update,
+            ));
+        } else if self.is_partially_cancelled() {
+            entries.push(entry(
+                "partially-cancelled",
+                format!("{line} stopper ikke på {}", self.cancelled_stops.join(", ")),
+                self.last_update,
+            ));
         }
+        if let Some(status) = self
+            .stuck_status()
+            .filter(|status| status.kind == StuckKind::Vehicle)
+        {
+            entries.push(entry(
+                "stuck",
+                format!("{line} er mulig stoppet"),
+                status.since,
+            ));
+        }
+        let delay = self.recorded_delay_seconds();
+        if delay > min_delay_seconds {
+            entries.push(entry(
+                "delayed",
+                format!("{line} er {} min forsinket", delay / 60),
+                self.prev_stop_actual_time,
+            ));
+        }
+        entries
     }
 }
 
-#[derive(Clone)]
+/// Counts of journeys in a particular state, used for reporting
+#[derive(Default, Debug)]
+pub struct ModeSummary {
+    pub journeys: usize,
+    pub delayed: usize,
+    pub possibly_stuck: usize,
+    pub data_stale: usize,
+    pub cancelled: usize,
+}
+
+impl ModeSummary {
+    pub fn add(&mut self, journey: &Journey) {
+        self.journeys += 1;
+        if journey.cancelled {
+            self.cancelled += 1;
+        } else if let Some(status) = journey.stuck_status() {
+            if status.kind == StuckKind::Vehicle {
+                self.possibly_stuck += 1;
+            } else {
+                self.data_stale += 1;
+            }
+        }
+        if journey.recorded_delay_seconds() > 60 {
+            self.delayed += 1;
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct Journeys {
-    journeys: FxHashMap<JourneyId, Journey>,
+    /// Shared with earlier versions of the state, so that copying it for the next version is cheap
+    journeys: FxHashMap<JourneyId, Arc<Journey>>,
+    /// From the most recent batch of journeys from each data source in each feed
+    quality: FxHashMap<(Arc<str>, String), SourceQuality>,
+    /// Accumulated over every batch
+    unresolved: UnresolvedStops,
 }
 
 impl Journeys {
+    pub fn iter(&self) -> impl Iterator<Item = &Journey> {
+        self.journeys.values().map(Arc::as_ref)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Journey> {
+        self.journeys
+            .get(&JourneyId(id.to_string()))
+            .map(Arc::as_ref)
+    }
+
     pub fn by_visits(&self, stop_name: &str) -> Vec<&Journey> {
         self.journeys
             .values()
             .filter(|journey| journey.to_visit.contains(stop_name))
+            .map(Arc::as_ref)
+            .collect()
+    }
+
+    /// Journeys that will visit any of the quays in `area`
+    pub fn by_area(&self, area: &StopArea) -> Vec<&Journey> {
+        self.journeys
+            .values()
+            .filter(|journey| !journey.to_visit_quays.is_disjoint(&area.quay_refs))
+            .map(Arc::as_ref)
+            .collect()
+    }
+
+    pub fn by_line(&self, line: &str) -> Vec<&Journey> {
+        self.journeys
+            .values()
+            .filter(|journey| journey.on_line(line))
+            .map(Arc::as_ref)
             .collect()
     }
 
@@ -252,38 +1044,75 @@ impl Journeys {
         self.journeys
             .values()
             .filter(|journey| train_ds.contains(&journey.data_source.as_str()))
+            .map(Arc::as_ref)
             .collect()
     }
 
-    pub fn new(stops: &Stops, journeys: impl Iterator<Item = EstimatedVehicleJourney>) -> Self {
-        let mut journeys: Vec<_> = journeys.collect();
-        journeys.sort_by_key(|journey| journey.recorded_at_time);
-        let journeys = journeys
-            .into_iter()
-            .filter_map(|journey_row| {
-                let id = journey_row
-                    .dated_vehicle_journey_ref
-                    .as_ref()
-                    .map(|r| r.value.as_str())
-                    .or_else(|| {
-                        journey_row
-                            .framed_vehicle_journey_ref
-                            .as_ref()
-                            .map(|r| r.dated_vehicle_journey_ref.as_str())
-                    })
-                    .or_else(|| journey_row.block_ref.as_ref().map(|r| r.value.as_str()))
-                    .map(|id| id.to_string())?;
-                let mapped = Journey::new(stops, JourneyId(id.clone()), journey_row);
-                Some((JourneyId(id.clone()), mapped?))
-            })
-            .collect();
-        Self { journeys }
+    /// Keep `journey`, unless we already have a newer version of it
+    fn insert_newest(&mut self, id: JourneyId, journey: Arc<Journey>) {
+        match self.journeys.entry(id) {
+            Entry::Occupied(mut existing) => {
+                if journey.last_update >= existing.get().last_update {
+                    existing.insert(journey);
+                }
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(journey);
+            }
+        }
     }
 
+    /// When both have the same journey, possibly from different feeds, we keep the one with the
+    /// newest RecordedAtTime, or the one from `other` if they were recorded at the same time.
     pub fn merge_from(&mut self, other: Journeys) {
         for (id, journey) in other.journeys.into_iter() {
-            self.journeys.insert(id, journey);
+            self.insert_newest(id, journey);
         }
+        self.quality.extend(other.quality);
+        self.unresolved.merge(other.unresolved);
+    }
+
+    /// Journeys from a snapshot, with the feed we received them from. They weren't received in a
+    /// fetch, so they don't count towards the data quality.
+    pub fn restore(
+        stops: &Stops,
+        stuck: &StuckConfig,
+        journeys: impl Iterator<Item = (Arc<str>, String, JourneySource)>,
+    ) -> Self {
+        let mut by_feed: FxHashMap<Arc<str>, Vec<(String, JourneySource)>> = FxHashMap::default();
+        for (feed, id, journey) in journeys {
+            by_feed.entry(feed).or_default().push((id, journey));
+        }
+        let mut restored = Self::default();
+        for (feed, journeys) in by_feed {
+            let mut builder = JourneysBuilder::new(stops, stuck, feed);
+            for (id, journey) in journeys {
+                builder.add_source(Some(id), journey);
+            }
+            restored.merge_from(builder.build());
+        }
+        restored.quality.clear();
+        restored
+    }
+
+    /// What we kept of the journeys we received, with their feed and id
+    pub fn sources(&self) -> impl Iterator<Item = (&str, &str, &JourneySource)> {
+        self.journeys
+            .values()
+            .map(|journey| (journey.feed(), journey.id(), journey.source.as_ref()))
+    }
+
+    /// Resolve the stops of every journey again, after the stop registry has changed. Journeys
+    /// we can no longer resolve are kept as they were.
+    pub fn resolve(&mut self, stops: &Stops, stuck: &StuckConfig) {
+        let mut unresolved = UnresolvedStops::default();
+        for journey in self.journeys.values_mut() {
+            unresolved.observe(stops, Some(journey.id()), &journey.source);
+            if let Some(resolved) = journey.resolve(stops, stuck) {
+                *journey = Arc::new(resolved);
+            }
+        }
+        self.unresolved = unresolved;
     }
 
     pub fn expire(&mut self, cutoff: DateTime<FixedOffset>) {
@@ -291,50 +1120,714 @@ impl Journeys {
             .retain(|_, journey| journey.last_update > cutoff);
     }
 
+    /// Link cancelled journeys to the extra journeys that replace them, in both directions. This
+    /// compares every cancelled journey with every extra journey, so call it once, after the
+    /// journeys have been merged, expired or resolved, and before they are published.
+    pub fn link_replacements(&mut self) {
+        let extras: Vec<_> = self
+            .journeys
+            .values()
+            .filter(|journey| journey.extra && !journey.cancelled)
+            .collect();
+        let links: Vec<(JourneyId, JourneyId)> = self
+            .journeys
+            .values()
+            .filter(|journey| journey.cancelled || journey.is_partially_cancelled())
+            .flat_map(|cancelled| {
+                extras
+                    .iter()
+                    .filter(|extra| extra.journey_id != cancelled.journey_id)
+                    .filter(|extra| extra.could_replace(cancelled))
+                    .map(|extra| (cancelled.journey_id.clone(), extra.journey_id.clone()))
+            })
+            .collect();
+
+        let mut replaced_by: FxHashMap<JourneyId, Vec<JourneyLink>> = FxHashMap::default();
+        let mut replaces: FxHashMap<JourneyId, Vec<JourneyLink>> = FxHashMap::default();
+        for (cancelled, extra) in links {
+            let extra_link = JourneyLink::from(self.journeys[&extra].as_ref());
+            let cancelled_link = JourneyLink::from(self.journeys[&cancelled].as_ref());
+            replaced_by.entry(cancelled).or_default().push(extra_link);
+            replaces.entry(extra).or_default().push(cancelled_link);
+        }
+        // Only copy the journeys whose links have changed, the rest are shared with earlier states
+        for (id, journey) in self.journeys.iter_mut() {
+            let mut new_replaced_by = replaced_by.remove(id).unwrap_or_default();
+            let mut new_replaces = replaces.remove(id).unwrap_or_default();
+            new_replaced_by.sort_by(|a, b| a.id.cmp(&b.id));
+            new_replaces.sort_by(|a, b| a.id.cmp(&b.id));
+            if journey.replaced_by != new_replaced_by || journey.replaces != new_replaces {
+                let journey = Arc::make_mut(journey);
+                journey.replaced_by = new_replaced_by;
+                journey.replaces = new_replaces;
+            }
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.journeys.len()
     }
+
+    /// With the feed and data source they are from
+    pub fn quality(&self) -> impl Iterator<Item = (&str, &str, &SourceQuality)> {
+        self.quality
+            .iter()
+            .map(|((feed, data_source), quality)| (feed.as_ref(), data_source.as_str(), quality))
+    }
+
+    pub fn unresolved_stops(&self) -> &UnresolvedStops {
+        &self.unresolved
+    }
+
+    /// The newest `recorded_at_time` among journeys in state, per data source
+    pub fn last_update_by_data_source(&self) -> FxHashMap<&str, DateTime<FixedOffset>> {
+        let mut newest: FxHashMap<&str, DateTime<FixedOffset>> = FxHashMap::default();
+        for journey in self.journeys.values() {
+            let entry = newest
+                .entry(journey.data_source.as_str())
+                .or_insert(journey.last_update);
+            *entry = (*entry).max(journey.last_update);
+        }
+        newest
+    }
+
+    pub fn count_by_data_source(&self) -> FxHashMap<&str, usize> {
+        let mut counts = FxHashMap::default();
+        for journey in self.journeys.values() {
+            *counts.entry(journey.data_source.as_str()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// The number of journeys in state and the newest `recorded_at_time` among them, per feed
+    /// and data source
+    pub fn count_by_feed_and_data_source(
+        &self,
+    ) -> FxHashMap<(&str, &str), (usize, DateTime<FixedOffset>)> {
+        let mut counts: FxHashMap<(&str, &str), (usize, DateTime<FixedOffset>)> =
+            FxHashMap::default();
+        for journey in self.journeys.values() {
+            let (count, newest) = counts
+                .entry((journey.feed(), journey.data_source()))
+                .or_insert((0, journey.last_update));
+            *count += 1;
+            *newest = (*newest).max(journey.last_update);
+        }
+        counts
+    }
+
+    pub fn summary_by_mode(&self) -> FxHashMap<&str, ModeSummary> {
+        let mut summaries: FxHashMap<&str, ModeSummary> = FxHashMap::default();
+        for journey in self.journeys.values() {
+            summaries
+                .entry(journey.mode.as_str())
+                .or_default()
+                .add(journey);
+        }
+        summaries
+    }
 }
 
-impl From<Journey> for JourneyDelay {
-    fn from(value: Journey) -> Self {
-        let recorded_delay_seconds = value.recorded_delay_seconds();
+/// Builds the journeys from one fetch of a feed, one journey at a time as we parse them. When
+/// the same journey shows up more than once, we keep the version with the newest RecordedAtTime.
+pub struct JourneysBuilder<'a> {
+    stops: &'a Stops,
+    stuck: &'a StuckConfig,
+    feed: Arc<str>,
+    now: DateTime<FixedOffset>,
+    journeys: Journeys,
+}
 
+impl<'a> JourneysBuilder<'a> {
+    pub fn new(stops: &'a Stops, stuck: &'a StuckConfig, feed: Arc<str>) -> Self {
         Self {
-            vehicle_journey_id: value.journey_id.0,
-            line_ref: value.line_ref,
-            last_stop_name: value.prev_stop.name,
-            aimed_last_stop_time: value.prev_stop_planned_time,
-            actual_last_stop_time: value.prev_stop_actual_time,
-            recorded_delay_seconds,
-            next_stop_name: value.next_stop.map(|s| s.name),
-            aimed_next_stop_time: value.next_stop_planned_time,
+            stops,
+            stuck,
+            feed,
+            now: Utc::now().fixed_offset(),
+            journeys: Journeys::default(),
+        }
+    }
+
+    pub fn stops(&self) -> &Stops {
+        self.stops
+    }
+
+    pub fn add(&mut self, journey_row: EstimatedVehicleJourney) {
+        let id = journey_row
+            .dated_vehicle_journey_ref
+            .as_ref()
+            .map(|r| r.value.as_str())
+            .or_else(|| {
+                journey_row
+                    .framed_vehicle_journey_ref
+                    .as_ref()
+                    .map(|r| r.dated_vehicle_journey_ref.as_str())
+            })
+            .or_else(|| journey_row.block_ref.as_ref().map(|r| r.value.as_str()))
+            .map(|id| id.to_string());
+        self.add_source(id, JourneySource::from(journey_row));
+    }
+
+    /// Add a journey we have already received once, eg. from a snapshot
+    pub fn add_source(&mut self, id: Option<String>, source: JourneySource) {
+        let stops = self.stops;
+        let data_source = source.data_source.clone();
+        let Journeys {
+            quality,
+            unresolved,
+            ..
+        } = &mut self.journeys;
+        let quality = quality
+            .entry((self.feed.clone(), data_source.clone()))
+            .or_insert_with(|| SourceQuality::new(self.now));
+        // Look up the stop of each call once, for both the data quality and the unresolved stops
+        let mut inaccurate = source.prediction_inaccurate;
+        for call in source.calls() {
+            inaccurate |= call.prediction_inaccurate;
+            let stop = CallStop::of(stops, call);
+            quality.observe_call(stop);
+            if stop != CallStop::Known {
+                unresolved.observe_call(id.as_deref(), &data_source, call);
+            }
+        }
+        quality.observe(&source, inaccurate);
+        // Journeys that haven't started are left out on purpose, the rest are data problems
+        let started = source.started();
+        let mapped = id.and_then(|id| {
+            Journey::new(
+                stops,
+                self.stuck,
+                JourneyId(id.clone()),
+                Arc::new(source),
+                self.feed.clone(),
+            )
+            .map(|journey| (JourneyId(id), journey))
+        });
+        match mapped {
+            Some((id, journey)) => self.journeys.insert_newest(id, Arc::new(journey)),
+            None if started => {
+                if let Some(source) = self
+                    .journeys
+                    .quality
+                    .get_mut(&(self.feed.clone(), data_source))
+                {
+                    source.dropped += 1;
+                }
+            }
+            None => {}
         }
     }
+
+    pub fn build(self) -> Journeys {
+        self.journeys
+    }
 }
 
-impl From<Journey> for TrainJourney {
-    fn from(value: Journey) -> Self {
-        let recorded_delay_seconds = value.recorded_delay_seconds();
-        let possibly_stuck = value.possibly_stuck();
-        Self {
-            vehicle_journey_id: value.journey_id.0,
-            line_ref: format!(
-                "{}: {} to {}",
-                value.line_ref.split(':').next_back().unwrap(),
-                value.origin.name.trim_end_matches(" stasjon"),
-                value.destination.name.trim_end_matches(" stasjon")
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, minutes_from_now};
+
+    /// A journey that left its previous stop on time, should have reached the next stop
+    /// `overdue_minutes` ago, and that we last heard about `silent_minutes` ago
+    fn overdue(overdue_minutes: i64, silent_minutes: i64) -> JourneySource {
+        let next = minutes_from_now(-overdue_minutes);
+        JourneySource {
+            recorded_at_time: minutes_from_now(-silent_minutes),
+            ..testing::source(
+                "VYG:Line:R10",
+                vec![testing::visited(
+                    "Oslo S",
+                    next - TimeDelta::minutes(10),
+                    TimeDelta::zero(),
+                )],
+                vec![testing::call("Lillestrøm", next)],
+            )
+        }
+    }
+
+    fn stuck_kind(stuck: &StuckConfig, source: JourneySource) -> Option<StuckKind> {
+        let journeys = testing::journeys_with(stuck, [("1", source)]);
+        let journey = journeys.get("1").unwrap();
+        let kind = journey.stuck_status().map(|status| status.kind);
+        assert_eq!(journey.overdue(), kind.is_some());
+        assert_eq!(journey.possibly_stuck(), kind == Some(StuckKind::Vehicle));
+        assert_eq!(
+            journey.data_stale(),
+            kind.is_some_and(|k| k != StuckKind::Vehicle)
+        );
+        kind
+    }
+
+    #[test]
+    fn stuck_after_the_cushion_with_recent_data() {
+        let stuck = StuckConfig::default();
+        // The default cushion is 8 minutes, and data is stale after 10
+        assert_eq!(stuck_kind(&stuck, overdue(7, 1)), None);
+        assert_eq!(stuck_kind(&stuck, overdue(9, 1)), Some(StuckKind::Vehicle));
+        assert_eq!(stuck_kind(&stuck, overdue(9, 9)), Some(StuckKind::Vehicle));
+        assert_eq!(
+            stuck_kind(&stuck, overdue(9, 11)),
+            Some(StuckKind::DataStale)
+        );
+        // Stale data alone doesn't make a journey overdue
+        assert_eq!(stuck_kind(&stuck, overdue(-5, 30)), None);
+
+        let not_monitored = JourneySource {
+            monitored: false,
+            ..overdue(9, 1)
+        };
+        assert_eq!(
+            stuck_kind(&stuck, not_monitored),
+            Some(StuckKind::NotMonitored)
+        );
+        let cancelled = JourneySource {
+            cancelled: true,
+            ..overdue(30, 1)
+        };
+        assert_eq!(stuck_kind(&stuck, cancelled), None);
+    }
+
+    #[test]
+    fn stuck_rules_apply_by_mode_and_data_source() {
+        let stuck = StuckConfig::from_toml(
+            r#"
+            [[rules]]
+            mode = "rail"
+            cushion_minutes = 4
+
+            [[rules]]
+            data_source = "VYG"
+            stale_after_minutes = 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(stuck_kind(&stuck, overdue(5, 1)), Some(StuckKind::Vehicle));
+        assert_eq!(
+            stuck_kind(&stuck, overdue(5, 4)),
+            Some(StuckKind::DataStale)
+        );
+        // The rules for rail and VYG don't apply to buses from RUT
+        let bus = JourneySource {
+            mode: Some("bus".to_string()),
+            data_source: "RUT".to_string(),
+            ..overdue(5, 4)
+        };
+        assert_eq!(stuck_kind(&stuck, bus), None);
+        let bus = JourneySource {
+            mode: Some("bus".to_string()),
+            data_source: "RUT".to_string(),
+            ..overdue(9, 4)
+        };
+        assert_eq!(stuck_kind(&stuck, bus), Some(StuckKind::Vehicle));
+    }
+
+    /// A call planned to arrive `arrival` minutes after the start and dwell `dwell` seconds,
+    /// expected `expected_delay` seconds late
+    fn planned(arrival: i64, dwell: i64, expected_delay: i64) -> SourceCall {
+        let start = DateTime::parse_from_rfc3339("2026-10-18T10:00:00+02:00").unwrap();
+        let arrival = start + TimeDelta::minutes(arrival);
+        let departure = arrival + TimeDelta::seconds(dwell);
+        let expected = TimeDelta::seconds(expected_delay);
+        SourceCall {
+            aimed_departure_time: Some(departure),
+            expected_arrival_time: Some(arrival + expected),
+            expected_departure_time: Some(departure + expected),
+            ..testing::call(&format!("Stop {arrival}"), arrival)
+        }
+    }
+
+    fn modelled(current_delay: i64, calls: &[SourceCall]) -> Vec<i32> {
+        let stops = Stops::new(Vec::new(), Vec::new());
+        let calls: Vec<_> = calls.iter().collect();
+        let start = calls[0].aimed_arrival_time.unwrap();
+        predict_delays(
+            &stops,
+            &calls,
+            TimeDelta::seconds(current_delay),
+            start,
+            false,
+        )
+        .iter()
+        .map(|prediction| prediction.modelled_delay_seconds)
+        .collect()
+    }
+
+    #[test]
+    fn recovers_from_running_and_dwell_slack() {
+        // The previous departure is at 0, so the first call is where we are
+        let cases = [
+            (
+                "5% of each 20 minute run",
+                600,
+                vec![planned(0, 0, 0), planned(20, 0, 0), planned(40, 0, 0)],
+                vec![600, 540, 480],
+            ),
+            (
+                "and all but 30 s of each dwell",
+                600,
+                vec![planned(0, 180, 0), planned(23, 60, 0), planned(44, 0, 0)],
+                vec![600, 390, 300],
+            ),
+            (
+                "but not dwells shorter than 30 s",
+                600,
+                vec![planned(0, 20, 0), planned(20, 0, 0)],
+                vec![600, 541],
+            ),
+            (
+                "never ahead of schedule",
+                90,
+                vec![planned(0, 0, 0), planned(20, 0, 0), planned(40, 0, 0)],
+                vec![90, 30, 0],
+            ),
+            (
+                "on time stays on time",
+                0,
+                vec![planned(0, 300, 0), planned(20, 0, 0)],
+                vec![0, 0],
+            ),
+        ];
+        for (case, delay, calls, expected) in cases {
+            assert_eq!(modelled(delay, &calls), expected, "{case}");
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_model_when_the_producer_is_unreliable() {
+        let stops = Stops::new(Vec::new(), Vec::new());
+        let reliable = planned(20, 0, 300);
+        let inaccurate = SourceCall {
+            prediction_inaccurate: true,
+            ..reliable.clone()
+        };
+        let low_quality = SourceCall {
+            low_quality: true,
+            ..reliable.clone()
+        };
+        // The producer expects 300 s, the model 600 - 60 s
+        let cases = [
+            ("reliable", &reliable, false, 300, true),
+            ("inaccurate journey", &reliable, true, 540, false),
+            ("inaccurate call", &inaccurate, false, 540, false),
+            ("low quality", &low_quality, false, 540, false),
+        ];
+        for (case, call, journey_inaccurate, delay, by_producer) in cases {
+            let start = planned(0, 0, 0).aimed_departure_time.unwrap();
+            let predictions = predict_delays(
+                &stops,
+                &[call],
+                TimeDelta::seconds(600),
+                start,
+                journey_inaccurate,
+            );
+            let prediction = &predictions[0];
+            assert_eq!(prediction.expected_delay_seconds, Some(300), "{case}");
+            assert_eq!(prediction.modelled_delay_seconds, 540, "{case}");
+            assert_eq!(prediction.delay_seconds(), delay, "{case}");
+            assert_eq!(prediction.by_producer(), by_producer, "{case}");
+            assert!(prediction.expected_delayed(), "{case}");
+        }
+    }
+
+    #[test]
+    fn uses_the_model_without_expected_times() {
+        let prediction = |expected: Option<i32>, unreliable: bool| DelayPrediction {
+            stop_name: "Lillestrøm".to_string(),
+            aimed_time: testing::minutes_from_now(0),
+            expected_delay_seconds: expected,
+            modelled_delay_seconds: 120,
+            unreliable,
+        };
+        // (expected, unreliable) => (delay_seconds, by_producer, expected_delayed)
+        let cases = [
+            ((None, false), (120, false, false)),
+            ((None, true), (120, false, false)),
+            ((Some(60), false), (60, true, false)),
+            ((Some(61), false), (61, true, true)),
+            ((Some(-30), false), (-30, true, false)),
+            ((Some(600), true), (120, false, true)),
+        ];
+        for ((expected, unreliable), (delay, by_producer, delayed)) in cases {
+            let prediction = prediction(expected, unreliable);
+            assert_eq!(
+                (
+                    prediction.delay_seconds(),
+                    prediction.by_producer(),
+                    prediction.expected_delayed()
+                ),
+                (delay, by_producer, delayed),
+                "{expected:?} {unreliable}"
+            );
+        }
+    }
+
+    /// Left Oslo S and will call at the stops in `estimated`, 10 minutes apart
+    fn from_oslo(estimated: impl IntoIterator<Item = SourceCall>) -> JourneySource {
+        let start = minutes_from_now(-5);
+        testing::source(
+            "VYG:Line:R10",
+            vec![testing::visited("Oslo S", start, TimeDelta::zero())],
+            estimated.into_iter().collect(),
+        )
+    }
+
+    fn at(name: &str, minutes: i64) -> SourceCall {
+        testing::call(name, minutes_from_now(minutes))
+    }
+
+    fn visits(journeys: &Journeys, stop: &str) -> Vec<String> {
+        let mut ids: Vec<_> = journeys
+            .by_visits(stop)
+            .iter()
+            .map(|journey| journey.id().to_string())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn skips_cancelled_stops() {
+        let journeys = testing::journeys([
+            (
+                "skips",
+                from_oslo([
+                    at("Lillestrøm", 5),
+                    testing::cancelled(at("Kløfta", 15)),
+                    at("Eidsvoll", 25),
+                ]),
+            ),
+            (
+                "stops",
+                from_oslo([at("Lillestrøm", 5), at("Kløfta", 15), at("Eidsvoll", 25)]),
+            ),
+        ]);
+        assert_eq!(visits(&journeys, "Kløfta"), ["stops"]);
+        assert_eq!(visits(&journeys, "Eidsvoll"), ["skips", "stops"]);
+
+        let skips = journeys.get("skips").unwrap();
+        assert!(skips.is_partially_cancelled());
+        assert!(!skips.is_cancelled());
+        assert_eq!(skips.cancelled_stops(), ["Kløfta"]);
+        assert_eq!(skips.short_turned_at(), None);
+        assert_eq!(
+            skips.planned_stops(),
+            ["Oslo S", "Lillestrøm", "Kløfta", "Eidsvoll"]
+        );
+        assert!(!journeys.get("stops").unwrap().is_partially_cancelled());
+    }
+
+    #[test]
+    fn skips_a_cancelled_next_stop() {
+        let journeys = testing::journeys([(
+            "1",
+            from_oslo([testing::cancelled(at("Lillestrøm", 5)), at("Kløfta", 15)]),
+        )]);
+        let journey = journeys.get("1").unwrap();
+        assert_eq!(journey.next_stop_name(), Some("Kløfta"));
+        assert!(visits(&journeys, "Lillestrøm").is_empty());
+    }
+
+    #[test]
+    fn short_turns_when_the_last_stops_are_cancelled() {
+        let journeys = testing::journeys([
+            (
+                "short",
+                from_oslo([
+                    at("Lillestrøm", 5),
+                    testing::cancelled(at("Kløfta", 15)),
+                    testing::cancelled(at("Eidsvoll", 25)),
+                ]),
+            ),
+            (
+                "at once",
+                from_oslo([
+                    testing::cancelled(at("Lillestrøm", 5)),
+                    testing::cancelled(at("Kløfta", 15)),
+                ]),
+            ),
+        ]);
+        let short = journeys.get("short").unwrap();
+        assert_eq!(short.short_turned_at(), Some("Lillestrøm"));
+        assert_eq!(short.cancelled_stops(), ["Kløfta", "Eidsvoll"]);
+        assert_eq!(visits(&journeys, "Lillestrøm"), ["short"]);
+        assert!(visits(&journeys, "Eidsvoll").is_empty());
+        // It terminates where it is
+        let at_once = journeys.get("at once").unwrap();
+        assert_eq!(at_once.short_turned_at(), Some("Oslo S"));
+        assert_eq!(at_once.next_stop_name(), None);
+    }
+
+    #[test]
+    fn a_cancelled_journey_keeps_its_stops() {
+        let cancelled = JourneySource {
+            cancelled: true,
+            ..from_oslo([
+                testing::cancelled(at("Lillestrøm", 5)),
+                testing::cancelled(at("Kløfta", 15)),
+            ])
+        };
+        let journeys = testing::journeys([("1", cancelled)]);
+        let journey = journeys.get("1").unwrap();
+        assert!(journey.is_cancelled());
+        assert!(!journey.is_partially_cancelled());
+        assert_eq!(journey.short_turned_at(), None);
+        assert!(journey.cancelled_stops().is_empty());
+        assert_eq!(visits(&journeys, "Kløfta"), ["1"]);
+    }
+
+    /// An unstarted journey on `line_ref` calling at `stops`, 10 minutes apart from `start`
+    fn planned_journey(line_ref: &str, start: i64, stops: &[&str]) -> JourneySource {
+        let calls = stops
+            .iter()
+            .zip((start..).step_by(10))
+            .map(|(name, minutes)| at(name, minutes))
+            .collect();
+        testing::source(line_ref, Vec::new(), calls)
+    }
+
+    fn cancelled_journey(line_ref: &str, start: i64, stops: &[&str]) -> JourneySource {
+        JourneySource {
+            cancelled: true,
+            ..planned_journey(line_ref, start, stops)
+        }
+    }
+
+    fn extra_journey(line_ref: &str, start: i64, stops: &[&str]) -> JourneySource {
+        JourneySource {
+            extra: true,
+            mode: Some("bus".to_string()),
+            ..planned_journey(line_ref, start, stops)
+        }
+    }
+
+    fn replaced_by(journeys: &Journeys, id: &str) -> Vec<String> {
+        let journey = journeys.get(id).unwrap();
+        journey
+            .replaced_by()
+            .iter()
+            .map(|link| link.id.clone())
+            .collect()
+    }
+
+    fn replaces(journeys: &Journeys, id: &str) -> Vec<String> {
+        let journey = journeys.get(id).unwrap();
+        journey
+            .replaces()
+            .iter()
+            .map(|link| link.id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn links_cancelled_journeys_to_their_replacements() {
+        let stops = ["Oslo S", "Lillestrøm", "Kløfta", "Eidsvoll"];
+        let journeys = testing::journeys([
+            ("cancelled", cancelled_journey("VYG:Line:R10", 30, &stops)),
+            // Two of the cancelled stops, on another line
+            (
+                "bus",
+                extra_journey("VYG:Line:R10X", 40, &["Lillestrøm", "Kløfta"]),
+            ),
+            // One on the same line, starting within the hour after the cancelled one ends
+            (
+                "same line",
+                extra_journey("VYG:Line:R10", 110, &["Eidsvoll", "Hamar"]),
+            ),
+        ]);
+        assert_eq!(replaced_by(&journeys, "cancelled"), ["bus", "same line"]);
+        assert_eq!(replaces(&journeys, "bus"), ["cancelled"]);
+        assert_eq!(replaces(&journeys, "same line"), ["cancelled"]);
+        let link = &journeys.get("cancelled").unwrap().replaced_by()[0];
+        assert_eq!(link.mode, "bus");
+        assert_eq!(link.line, "R10X: Lillestrøm to Kløfta");
+    }
+
+    #[test]
+    fn doesnt_link_what_doesnt_replace() {
+        let stops = ["Oslo S", "Lillestrøm", "Kløfta", "Eidsvoll"];
+        let journeys = testing::journeys([
+            ("cancelled", cancelled_journey("VYG:Line:R10", 30, &stops)),
+            // Only one stop, on another line
+            (
+                "one stop",
+                extra_journey("VYG:Line:L1", 30, &["Lillestrøm", "Lysaker"]),
+            ),
+            // More than an hour before or after
+            (
+                "before",
+                extra_journey("VYG:Line:R10", -60, &["Oslo S", "Lillestrøm"]),
+            ),
+            (
+                "after",
+                extra_journey("VYG:Line:R10", 130, &["Kløfta", "Eidsvoll"]),
             ),
-            cancellation: value.cancelled,
-            data_source: value.data_source,
-            stop_name: value.prev_stop.name,
-            next_stop_name: value.next_stop.map(|s| s.name),
-            aimed_time: value.prev_stop_planned_time,
-            actual_time: value.prev_stop_actual_time,
-            delay_seconds: recorded_delay_seconds,
-            next_stop_time: value.next_stop_planned_time,
-            departed: true,
-            possibly_stuck,
+            // Elsewhere
+            (
+                "elsewhere",
+                extra_journey("VYG:Line:R10", 30, &["Drammen", "Asker"]),
+            ),
+            // Journeys that aren't extra, or that are cancelled themselves
+            (
+                "planned",
+                from_oslo([at("Lillestrøm", 35), at("Kløfta", 45)]),
+            ),
+            (
+                "cancelled extra",
+                JourneySource {
+                    cancelled: true,
+                    ..extra_journey("VYG:Line:R10", 30, &stops)
+                },
+            ),
+        ]);
+        assert!(replaced_by(&journeys, "cancelled").is_empty());
+        for id in ["one stop", "before", "after", "elsewhere", "planned"] {
+            assert!(replaces(&journeys, id).is_empty(), "{id}");
         }
     }
+
+    #[test]
+    fn links_partially_cancelled_journeys_by_their_cancelled_stops() {
+        let partially = from_oslo([
+            at("Lillestrøm", 5),
+            testing::cancelled(at("Kløfta", 15)),
+            testing::cancelled(at("Eidsvoll", 25)),
+        ]);
+        let journeys = testing::journeys([
+            ("partially", partially),
+            (
+                "bus",
+                extra_journey("VYG:Line:R10X", 10, &["Lillestrøm", "Kløfta", "Eidsvoll"]),
+            ),
+            // Only stops the train still calls at
+            (
+                "stops anyway",
+                extra_journey("VYG:Line:R10X", 0, &["Oslo S", "Lillestrøm"]),
+            ),
+        ]);
+        assert_eq!(replaced_by(&journeys, "partially"), ["bus"]);
+        assert!(replaces(&journeys, "stops anyway").is_empty());
+    }
+
+    #[test]
+    fn unlinks_replacements_that_are_gone() {
+        let stops = ["Oslo S", "Lillestrøm", "Kløfta"];
+        let mut journeys = testing::journeys([
+            ("cancelled", cancelled_journey("VYG:Line:R10", 30, &stops)),
+            (
+                "bus",
+                JourneySource {
+                    recorded_at_time: minutes_from_now(-60),
+                    ..extra_journey("VYG:Line:R10", 30, &stops)
+                },
+            ),
+        ]);
+        assert_eq!(replaced_by(&journeys, "cancelled"), ["bus"]);
+
+        journeys.expire(minutes_from_now(-30));
+        journeys.link_replacements();
+        assert!(journeys.get("bus").is_none());
+        assert!(replaced_by(&journeys, "cancelled").is_empty());
+    }
 }
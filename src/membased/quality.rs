@@ -0,0 +1,194 @@
+// Data quality counters per data source, collected while building journeys
+use crate::membased::{JourneySource, SourceCall, Stops};
+use chrono::{DateTime, FixedOffset};
+use fxhash::FxHashMap;
+
+/// What we found for the StopPointRef of a call
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CallStop {
+    Known,
+    /// Not in the stop registry
+    Unknown,
+    WithoutRef,
+}
+
+impl CallStop {
+    pub fn of(stops: &Stops, call: &SourceCall) -> Self {
+        match &call.stop_point_ref {
+            None => CallStop::WithoutRef,
+            Some(stop_point_ref) if stops.get(stop_point_ref).is_some() => CallStop::Known,
+            Some(_) => CallStop::Unknown,
+        }
+    }
+}
+
+/// What we saw from one data source in the most recent fetch that had journeys from it
+#[derive(Clone, Debug)]
+pub struct SourceQuality {
+    /// When the journeys were received
+    pub at: DateTime<FixedOffset>,
+    pub received: usize,
+    /// Journeys the producer doesn't track in real time
+    pub unmonitored: usize,
+    /// Journeys where the producer flagged the journey or any of its calls as inaccurate
+    pub prediction_inaccurate: usize,
+    /// Journeys that have started, but we couldn't use, because they lack an id, times or stops
+    /// we can name
+    pub dropped: usize,
+    pub calls: usize,
+    /// Calls with a StopPointRef that isn't in the stop registry
+    pub unknown_stop_refs: usize,
+    total_age_seconds: i64,
+    /// The oldest `recorded_at_time`, relative to `at`
+    pub max_age_seconds: i64,
+}
+
+impl SourceQuality {
+    pub fn new(at: DateTime<FixedOffset>) -> Self {
+        Self {
+            at,
+            received: 0,
+            unmonitored: 0,
+            prediction_inaccurate: 0,
+            dropped: 0,
+            calls: 0,
+            unknown_stop_refs: 0,
+            total_age_seconds: 0,
+            max_age_seconds: 0,
+        }
+    }
+
+    /// Count `journey`, before it is turned into a `Journey`. Its calls are counted with
+    /// `observe_call`, and `inaccurate` is set when the journey or any of its calls were flagged.
+    pub fn observe(&mut self, journey: &JourneySource, inaccurate: bool) {
+        self.received += 1;
+        if !journey.monitored {
+            self.unmonitored += 1;
+        }
+        if inaccurate {
+            self.prediction_inaccurate += 1;
+        }
+
+        let age = (self.at - journey.recorded_at_time).num_seconds().max(0);
+        self.total_age_seconds += age;
+        self.max_age_seconds = self.max_age_seconds.max(age);
+    }
+
+    pub fn observe_call(&mut self, stop: CallStop) {
+        self.calls += 1;
+        if stop == CallStop::Unknown {
+            self.unknown_stop_refs += 1;
+        }
+    }
+
+    /// The average age of `recorded_at_time`, relative to `at`
+    pub fn mean_age_seconds(&self) -> i64 {
+        self.total_age_seconds / self.received.max(1) as i64
+    }
+}
+
+/// Keep this many example journeys per unresolved StopPointRef
+const MAX_EXAMPLES: usize = 3;
+
+/// A StopPointRef we couldn't find in the stop registry
+#[derive(Clone, Debug)]
+pub struct UnresolvedStop {
+    pub calls: usize,
+    /// The StopPointName from SIRI, which we use instead. Calls without one are dropped.
+    pub fallback_name: Option<String>,
+    pub data_sources: Vec<String>,
+    pub example_journeys: Vec<String>,
+}
+
+/// StopPointRefs we couldn't find in the stop registry, and calls that have none
+#[derive(Clone, Default, Debug)]
+pub struct UnresolvedStops {
+    refs: FxHashMap<String, UnresolvedStop>,
+    /// Calls without a StopPointRef, which are always dropped
+    pub calls_without_ref: usize,
+}
+
+impl UnresolvedStops {
+    pub fn observe(&mut self, stops: &Stops, journey_id: Option<&str>, journey: &JourneySource) {
+        for call in journey.calls() {
+            if CallStop::of(stops, call) != CallStop::Known {
+                self.observe_call(journey_id, &journey.data_source, call);
+            }
+        }
+    }
+
+    /// Count a call whose StopPointRef isn't in the stop registry, or that has none
+    pub fn observe_call(&mut self, journey_id: Option<&str>, data_source: &str, call: &SourceCall) {
+        let Some(stop_point_ref) = &call.stop_point_ref else {
+            self.calls_without_ref += 1;
+            return;
+        };
+        let unresolved =
+            self.refs
+                .entry(stop_point_ref.clone())
+                .or_insert_with(|| UnresolvedStop {
+                    calls: 0,
+                    fallback_name: None,
+                    data_sources: Vec::new(),
+                    example_journeys: Vec::new(),
+                });
+        unresolved.calls += 1;
+        if unresolved.fallback_name.is_none() {
+            unresolved.fallback_name = call.stop_point_name.clone();
+        }
+        if !unresolved.data_sources.iter().any(|ds| ds == data_source) {
+            unresolved.data_sources.push(data_source.to_string());
+        }
+        if let Some(id) = journey_id
+            && unresolved.example_journeys.len() < MAX_EXAMPLES
+            && !unresolved
+                .example_journeys
+                .iter()
+                .any(|example| example == id)
+        {
+            unresolved.example_journeys.push(id.to_string());
+        }
+    }
+
+    /// Add the counts from `other`, which is usually from a later fetch
+    pub fn merge(&mut self, other: UnresolvedStops) {
+        self.calls_without_ref += other.calls_without_ref;
+        for (stop_point_ref, theirs) in other.refs {
+            let Some(ours) = self.refs.get_mut(&stop_point_ref) else {
+                self.refs.insert(stop_point_ref, theirs);
+                continue;
+            };
+            ours.calls += theirs.calls;
+            if ours.fallback_name.is_none() {
+                ours.fallback_name = theirs.fallback_name;
+            }
+            for data_source in theirs.data_sources {
+                if !ours.data_sources.contains(&data_source) {
+                    ours.data_sources.push(data_source);
+                }
+            }
+            for example in theirs.example_journeys {
+                if ours.example_journeys.len() < MAX_EXAMPLES
+                    && !ours.example_journeys.contains(&example)
+                {
+                    ours.example_journeys.push(example);
+                }
+            }
+        }
+    }
+
+    /// Unresolved StopPointRefs, most frequent first
+    pub fn by_count(&self) -> Vec<(&str, &UnresolvedStop)> {
+        let mut refs: Vec<_> = self
+            .refs
+            .iter()
+            .map(|(stop_point_ref, unresolved)| (stop_point_ref.as_str(), unresolved))
+            .collect();
+        refs.sort_by(|a, b| b.1.calls.cmp(&a.1.calls).then(a.0.cmp(b.0)));
+        refs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.refs.is_empty() && self.calls_without_ref == 0
+    }
+}
@@ -0,0 +1,216 @@
+// Fuzzy search for stop places by name
+use deunicode::deunicode;
+use fxhash::FxHashMap;
+
+/// Fold away accents and case, so that `Lillestrøm` and `lillestrom` are equal. The result is
+/// ASCII.
+fn normalize(s: &str) -> String {
+    deunicode(s)
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// How many typos we tolerate grows with the length of the query, short queries must be exact
+fn typos_allowed(query_len: usize) -> usize {
+    match query_len {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// A normalized name, with where each of its words start
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    /// Byte offsets, starting with 0. Normalized names are ASCII, so these are also characters.
+    word_starts: Vec<usize>,
+    /// The position of the stop place it refers to
+    place: usize,
+}
+
+impl Entry {
+    fn new(name: String, place: usize) -> Self {
+        let word_starts = std::iter::once(0)
+            .chain(name.match_indices(' ').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            name,
+            word_starts,
+            place,
+        }
+    }
+
+    /// The name from the start of each word
+    fn starts(&self) -> impl Iterator<Item = &str> {
+        self.word_starts.iter().map(|start| &self.name[*start..])
+    }
+
+    /// Lower is better, `None` is no match. `query` is normalized, and so ASCII.
+    fn score(&self, query: &str) -> Option<usize> {
+        let name = self.name.as_str();
+        if name == query {
+            return Some(0);
+        }
+        if name.starts_with(query) {
+            return Some(1);
+        }
+        if self.starts().any(|start| start.starts_with(query)) {
+            return Some(2);
+        }
+        if name.contains(query) {
+            return Some(3);
+        }
+
+        let query_len = query.len();
+        let allowed = typos_allowed(query_len);
+        if allowed == 0 {
+            return None;
+        }
+        // Compare with the start of the name and of each word, allowing the query to be a
+        // character shorter or longer, so that we can find the stop before the whole name has been
+        // typed.
+        let distance = self
+            .starts()
+            // Even the whole rest of the name is too short to be within `allowed` typos
+            .filter(|start| start.len() + allowed >= query_len)
+            // With at most `allowed` typos, one of the first `allowed + 1` characters of the query
+            // is among the first `2 * allowed + 2` characters of the start, possibly moved
+            .filter(|start| {
+                let head = &start.as_bytes()[..start.len().min(2 * allowed + 2)];
+                query.as_bytes()[..allowed + 1]
+                    .iter()
+                    .any(|c| head.contains(c))
+            })
+            .flat_map(|start| {
+                (query_len.saturating_sub(1)..=query_len + 1).map(move |len| {
+                    strsim::damerau_levenshtein(query, &start[..len.min(start.len())])
+                })
+            })
+            .min()?;
+        (distance <= allowed).then_some(4 + distance)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct StopIndex {
+    entries: Vec<Entry>,
+}
+
+impl StopIndex {
+    pub fn new<'a>(names: impl Iterator<Item = (usize, &'a str)>) -> Self {
+        let entries = names
+            .map(|(place, name)| (normalize(name), place))
+            .filter(|(name, _)| !name.is_empty())
+            .map(|(name, place)| Entry::new(name, place))
+            .collect();
+        Self { entries }
+    }
+
+    /// Positions of the stop places matching `query`, best matches first
+    pub fn search(&self, query: &str, limit: usize) -> Vec<usize> {
+        let query = normalize(query);
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let mut best: FxHashMap<usize, (usize, usize)> = FxHashMap::default();
+        for entry in self.entries.iter() {
+            if let Some(score) = entry.score(&query) {
+                let candidate = (score, entry.name.len());
+                best.entry(entry.place)
+                    .and_modify(|current| *current = candidate.min(*current))
+                    .or_insert(candidate);
+            }
+        }
+
+        let mut matches: Vec<_> = best.into_iter().collect();
+        matches.sort_by_key(|(place, rank)| (*rank, *place));
+        matches
+            .into_iter()
+            .take(limit)
+            .map(|(place, _)| place)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: [&str; 6] = [
+        "Lillestrøm stasjon",
+        "Lillehammer skysstasjon",
+        "Oslo S",
+        "Skøyen",
+        "Ås stasjon",
+        "Sandvika",
+    ];
+
+    fn search(query: &str) -> Vec<&'static str> {
+        let index = StopIndex::new(NAMES.iter().copied().enumerate());
+        index
+            .search(query, 10)
+            .into_iter()
+            .map(|place| NAMES[place])
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_case_accents_and_punctuation() {
+        assert_eq!(normalize("Lillestrøm stasjon"), "lillestrom stasjon");
+        assert_eq!(normalize("  ÆØÅ-æøå, "), "aeoa aeoa");
+        assert_eq!(normalize("Oslo S (tog)"), "oslo s tog");
+    }
+
+    #[test]
+    fn finds_prefixes_of_the_name_and_of_each_word() {
+        assert_eq!(
+            search("Lille"),
+            ["Lillestrøm stasjon", "Lillehammer skysstasjon"]
+        );
+        assert_eq!(search("oslo s"), ["Oslo S"]);
+        // Word prefixes before names that only contain the query, then shorter names first
+        assert_eq!(
+            search("stasjon"),
+            [
+                "Ås stasjon",
+                "Lillestrøm stasjon",
+                "Lillehammer skysstasjon"
+            ]
+        );
+        assert_eq!(search("skyss"), ["Lillehammer skysstasjon"]);
+    }
+
+    #[test]
+    fn ignores_case_and_accents() {
+        assert_eq!(search("lillestrom"), ["Lillestrøm stasjon"]);
+        assert_eq!(search("LILLESTRØM"), ["Lillestrøm stasjon"]);
+        assert_eq!(search("skoyen"), ["Skøyen"]);
+        assert_eq!(search("ås")[0], "Ås stasjon");
+        assert_eq!(search("as")[0], "Ås stasjon");
+    }
+
+    #[test]
+    fn tolerates_typos_in_longer_queries() {
+        assert_eq!(search("lilelstrom"), ["Lillestrøm stasjon"]);
+        assert_eq!(search("sandvkia"), ["Sandvika"]);
+        assert_eq!(search("skoyne"), ["Skøyen"]);
+        // Two typos from 8 characters
+        assert_eq!(search("lillestrxmm"), ["Lillestrøm stasjon"]);
+    }
+
+    #[test]
+    fn doesnt_match_what_isnt_there() {
+        assert!(search("bergen").is_empty());
+        // Short queries must be exact, longer ones may have a typo or two
+        assert!(search("osx").is_empty());
+        assert_eq!(search("oslx"), ["Oslo S"]);
+        assert!(search("sxndvxxa").is_empty());
+        assert!(search("").is_empty());
+        assert!(search("--").is_empty());
+    }
+}
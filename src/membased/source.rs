@@ -0,0 +1,163 @@
+// What we keep of the journeys we receive, so that we can resolve their stops again later
+use crate::entur_siriformat::{
+    EstimatedCall, EstimatedVehicleJourney, RecordedCall, StopAssignment, StringValue,
+};
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+/// The parts of a SIRI journey that we build a `Journey` from. We keep this instead of the whole
+/// `EstimatedVehicleJourney`, to resolve the stops again when the stop registry changes, and to
+/// save snapshots.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JourneySource {
+    pub recorded_at_time: DateTime<FixedOffset>,
+    pub data_source: String,
+    pub line_ref: String,
+    /// The first VehicleMode
+    pub mode: Option<String>,
+    pub cancelled: bool,
+    pub extra: bool,
+    pub monitored: bool,
+    pub prediction_inaccurate: bool,
+    pub recorded: Vec<SourceCall>,
+    pub estimated: Vec<SourceCall>,
+}
+
+/// A recorded or estimated call
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SourceCall {
+    pub stop_point_ref: Option<String>,
+    /// The first StopPointName, which we use when the StopPointRef isn't in the stop registry
+    pub stop_point_name: Option<String>,
+    pub aimed_arrival_time: Option<DateTime<FixedOffset>>,
+    pub aimed_departure_time: Option<DateTime<FixedOffset>>,
+    pub expected_arrival_time: Option<DateTime<FixedOffset>>,
+    pub expected_departure_time: Option<DateTime<FixedOffset>>,
+    /// Only recorded calls have these
+    pub actual_arrival_time: Option<DateTime<FixedOffset>>,
+    pub actual_departure_time: Option<DateTime<FixedOffset>>,
+    pub cancelled: bool,
+    pub prediction_inaccurate: bool,
+    /// The producer rated the expected arrival as low or very low quality
+    pub low_quality: bool,
+    pub arrival_platform_name: Option<String>,
+    pub departure_platform_name: Option<String>,
+    /// Set when the vehicle is expected to arrive at another quay than planned
+    pub arrival_quay_change: Option<QuayChange>,
+    /// Set when the vehicle is expected to depart from another quay than planned
+    pub departure_quay_change: Option<QuayChange>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuayChange {
+    pub aimed_quay_ref: String,
+    pub expected_quay_ref: String,
+}
+
+impl QuayChange {
+    fn new(assignment: Option<StopAssignment>) -> Option<Self> {
+        let assignment = assignment?;
+        let changed = assignment.aimed_quay_ref.value != assignment.expected_quay_ref.value;
+        changed.then_some(Self {
+            aimed_quay_ref: assignment.aimed_quay_ref.value,
+            expected_quay_ref: assignment.expected_quay_ref.value,
+        })
+    }
+}
+
+fn first_value(values: Option<Vec<StringValue>>) -> Option<String> {
+    values?.into_iter().next().map(|value| value.value)
+}
+
+impl From<RecordedCall> for SourceCall {
+    fn from(call: RecordedCall) -> Self {
+        Self {
+            stop_point_ref: call.stop_point_ref.map(|r| r.value),
+            stop_point_name: first_value(call.stop_point_name),
+            aimed_arrival_time: call.aimed_arrival_time,
+            aimed_departure_time: call.aimed_departure_time,
+            expected_arrival_time: call.expected_arrival_time,
+            expected_departure_time: call.expected_departure_time,
+            actual_arrival_time: call.actual_arrival_time,
+            actual_departure_time: call.actual_departure_time,
+            cancelled: call.cancellation.unwrap_or(false),
+            prediction_inaccurate: call.prediction_inaccurate.unwrap_or(false),
+            low_quality: false,
+            arrival_platform_name: call.arrival_platform_name.map(|p| p.value),
+            departure_platform_name: call.departure_platform_name.map(|p| p.value),
+            arrival_quay_change: None,
+            departure_quay_change: None,
+        }
+    }
+}
+
+impl From<EstimatedCall> for SourceCall {
+    fn from(call: EstimatedCall) -> Self {
+        let low_quality = call
+            .expected_arrival_prediction_quality
+            .is_some_and(|quality| {
+                let level = quality.prediction_level.to_lowercase();
+                level == "low" || level == "verylow"
+            });
+        Self {
+            stop_point_ref: call.stop_point_ref.map(|r| r.value),
+            stop_point_name: first_value(call.stop_point_name),
+            aimed_arrival_time: call.aimed_arrival_time,
+            aimed_departure_time: call.aimed_departure_time,
+            expected_arrival_time: call.expected_arrival_time,
+            expected_departure_time: call.expected_departure_time,
+            actual_arrival_time: None,
+            actual_departure_time: None,
+            cancelled: call.cancellation.unwrap_or(false),
+            prediction_inaccurate: call.prediction_inaccurate.unwrap_or(false),
+            low_quality,
+            arrival_platform_name: call.arrival_platform_name.map(|p| p.value),
+            departure_platform_name: call.departure_platform_name.map(|p| p.value),
+            arrival_quay_change: QuayChange::new(call.arrival_stop_assignment),
+            departure_quay_change: QuayChange::new(call.departure_stop_assignment),
+        }
+    }
+}
+
+impl From<EstimatedVehicleJourney> for JourneySource {
+    fn from(journey: EstimatedVehicleJourney) -> Self {
+        Self {
+            recorded_at_time: journey.recorded_at_time,
+            data_source: journey.data_source,
+            line_ref: journey.line_ref.value,
+            mode: journey
+                .vehicle_mode
+                .and_then(|modes| modes.into_iter().next()),
+            cancelled: journey.cancellation.unwrap_or(false),
+            extra: journey.extra_journey.unwrap_or(false),
+            monitored: journey.monitored.unwrap_or(true),
+            prediction_inaccurate: journey.prediction_inaccurate.unwrap_or(false),
+            recorded: journey
+                .recorded_calls
+                .map(|calls| calls.recorded_call)
+                .unwrap_or_default()
+                .into_iter()
+                .map(SourceCall::from)
+                .collect(),
+            estimated: journey
+                .estimated_calls
+                .map(|calls| calls.estimated_call)
+                .unwrap_or_default()
+                .into_iter()
+                .map(SourceCall::from)
+                .collect(),
+        }
+    }
+}
+
+impl JourneySource {
+    /// Both recorded and estimated calls, in order
+    pub fn calls(&self) -> impl Iterator<Item = &SourceCall> {
+        self.recorded.iter().chain(self.estimated.iter())
+    }
+
+    /// The journey has visited at least one stop
+    pub fn started(&self) -> bool {
+        !self.recorded.is_empty()
+    }
+}
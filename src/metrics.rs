@@ -0,0 +1,183 @@
+// Prometheus metrics for ingestion and serving
+use crate::membased::Journeys;
+use chrono::Utc;
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder, exponential_buckets,
+};
+
+pub struct Metrics {
+    registry: Registry,
+    pub fetch_duration: Histogram,
+    pub fetch_bytes: Histogram,
+    pub parse_duration: Histogram,
+    pub fetch_errors: IntCounterVec,
+    pub updated: IntCounter,
+    pub expired: IntCounter,
+    pub filtered: IntCounter,
+    pub http_requests: HistogramVec,
+    journeys: IntGaugeVec,
+    delayed: IntGaugeVec,
+    stuck: IntGaugeVec,
+    stale: IntGaugeVec,
+    cancelled: IntGaugeVec,
+    last_successful_sync: IntGauge,
+    seconds_since_last_successful_sync: Gauge,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("forsinka".to_string()), None)?;
+
+        let fetch_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "fetch_duration_seconds",
+                "Time until upstream starts sending SIRI data",
+            )
+            .buckets(exponential_buckets(0.05, 2.0, 12)?),
+        )?;
+        let fetch_bytes = Histogram::with_opts(
+            HistogramOpts::new("fetch_size_bytes", "Size of downloaded SIRI data")
+                .buckets(exponential_buckets(1024.0, 4.0, 11)?),
+        )?;
+        let parse_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "parse_duration_seconds",
+                "Time spent reading and decoding SIRI data, as it arrives",
+            )
+            .buckets(exponential_buckets(0.01, 2.0, 12)?),
+        )?;
+        let fetch_errors = IntCounterVec::new(
+            Opts::new(
+                "fetch_errors_total",
+                "Failed attempts at fetching SIRI data, per feed and kind",
+            ),
+            &["feed", "kind"],
+        )?;
+        let updated = IntCounter::new(
+            "journeys_updated_total",
+            "Journeys received from upstream and merged into state",
+        )?;
+        let expired = IntCounter::new(
+            "journeys_expired_total",
+            "Journeys removed from state because they have not been updated recently",
+        )?;
+        let filtered = IntCounter::new(
+            "journeys_filtered_total",
+            "Journeys left out by the ingestion filters while parsing",
+        )?;
+        let http_requests = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latencies"),
+            &["route", "status"],
+        )?;
+        let journeys = IntGaugeVec::new(
+            Opts::new("journeys", "Journeys in state per data source"),
+            &["data_source"],
+        )?;
+        let delayed = IntGaugeVec::new(
+            Opts::new("journeys_delayed", "Delayed journeys in state per mode"),
+            &["mode"],
+        )?;
+        let stuck = IntGaugeVec::new(
+            Opts::new(
+                "journeys_possibly_stuck",
+                "Possibly stuck journeys per mode",
+            ),
+            &["mode"],
+        )?;
+        let stale = IntGaugeVec::new(
+            Opts::new(
+                "journeys_data_stale",
+                "Overdue journeys we lack recent data for, per mode",
+            ),
+            &["mode"],
+        )?;
+        let cancelled = IntGaugeVec::new(
+            Opts::new("journeys_cancelled", "Cancelled journeys in state per mode"),
+            &["mode"],
+        )?;
+        let last_successful_sync = IntGauge::new(
+            "last_successful_sync_timestamp_seconds",
+            "Unix time of the last successful state replacement",
+        )?;
+        let seconds_since_last_successful_sync = Gauge::new(
+            "seconds_since_last_successful_sync",
+            "Seconds since the last successful state replacement",
+        )?;
+
+        registry.register(Box::new(fetch_duration.clone()))?;
+        registry.register(Box::new(fetch_bytes.clone()))?;
+        registry.register(Box::new(parse_duration.clone()))?;
+        registry.register(Box::new(fetch_errors.clone()))?;
+        registry.register(Box::new(updated.clone()))?;
+        registry.register(Box::new(expired.clone()))?;
+        registry.register(Box::new(filtered.clone()))?;
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(journeys.clone()))?;
+        registry.register(Box::new(delayed.clone()))?;
+        registry.register(Box::new(stuck.clone()))?;
+        registry.register(Box::new(stale.clone()))?;
+        registry.register(Box::new(cancelled.clone()))?;
+        registry.register(Box::new(last_successful_sync.clone()))?;
+        registry.register(Box::new(seconds_since_last_successful_sync.clone()))?;
+
+        Ok(Self {
+            registry,
+            fetch_duration,
+            fetch_bytes,
+            parse_duration,
+            fetch_errors,
+            updated,
+            expired,
+            filtered,
+            http_requests,
+            journeys,
+            delayed,
+            stuck,
+            stale,
+            cancelled,
+            last_successful_sync,
+            seconds_since_last_successful_sync,
+        })
+    }
+
+    /// Refresh the gauges that describe the current state. Call after every successful sync.
+    pub fn observe_state(&self, journeys: &Journeys) {
+        self.journeys.reset();
+        for (data_source, count) in journeys.count_by_data_source() {
+            self.journeys
+                .with_label_values(&[data_source])
+                .set(count as i64);
+        }
+
+        self.delayed.reset();
+        self.stuck.reset();
+        self.stale.reset();
+        self.cancelled.reset();
+        for (mode, summary) in journeys.summary_by_mode() {
+            self.delayed
+                .with_label_values(&[mode])
+                .set(summary.delayed as i64);
+            self.stuck
+                .with_label_values(&[mode])
+                .set(summary.possibly_stuck as i64);
+            self.stale
+                .with_label_values(&[mode])
+                .set(summary.data_stale as i64);
+            self.cancelled
+                .with_label_values(&[mode])
+                .set(summary.cancelled as i64);
+        }
+
+        self.last_successful_sync.set(Utc::now().timestamp());
+    }
+
+    pub fn render(&self) -> anyhow::Result<String> {
+        let since = Utc::now().timestamp() - self.last_successful_sync.get();
+        self.seconds_since_last_successful_sync.set(since as f64);
+
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
@@ -0,0 +1,294 @@
+// Query parameters for filtering, sorting and paginating lists of journeys
+use crate::membased::Journey;
+use anyhow::bail;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cmp::Reverse;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use utoipa::IntoParams;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SortKey {
+    Delay,
+    Line,
+    Stop,
+    Time,
+}
+
+/// A sort key, optionally prefixed with `-` to sort in descending order, eg. `-delay`.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(into = "String")]
+pub struct Sort {
+    key: SortKey,
+    descending: bool,
+}
+
+impl FromStr for Sort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (descending, key) = match s.strip_prefix('-') {
+            Some(key) => (true, key),
+            None => (false, s),
+        };
+        let key = match key {
+            "delay" => SortKey::Delay,
+            "line" => SortKey::Line,
+            "stop" => SortKey::Stop,
+            "time" => SortKey::Time,
+            _ => bail!("Unknown sort key: {s}"),
+        };
+        Ok(Self { key, descending })
+    }
+}
+
+impl Display for Sort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let key = match self.key {
+            SortKey::Delay => "delay",
+            SortKey::Line => "line",
+            SortKey::Stop => "stop",
+            SortKey::Time => "time",
+        };
+        if self.descending {
+            write!(f, "-{key}")
+        } else {
+            write!(f, "{key}")
+        }
+    }
+}
+
+impl From<Sort> for String {
+    fn from(value: Sort) -> Self {
+        value.to_string()
+    }
+}
+
+/// HTML forms submit empty fields as `field=`, which we treat as if the field was left out.
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Like `empty_as_none`, but a page must have room for at least one item.
+fn positive<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match empty_as_none(deserializer)? {
+        Some(0) => Err(serde::de::Error::custom("must be at least 1")),
+        value => Ok(value),
+    }
+}
+
+#[derive(Deserialize, Serialize, IntoParams, Default, Clone, Debug, PartialEq)]
+#[into_params(parameter_in = Query)]
+pub struct ListParams {
+    /// Only include journeys delayed by at least this many minutes
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_delay: Option<i32>,
+    /// Only include journeys from this data source, eg. VYG
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_source: Option<String>,
+    /// Only include journeys on this line, either the entire LineRef or the last part of it, eg. R10
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancelled: Option<bool>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stuck: Option<bool>,
+    /// One of `delay`, `line`, `stop` or `time`, prefixed with `-` for descending order.
+    /// Defaults to possibly stuck journeys first, then by descending delay.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[param(value_type = Option<String>)]
+    pub sort: Option<Sort>,
+    #[serde(default, deserialize_with = "positive")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[param(minimum = 1)]
+    pub limit: Option<usize>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+}
+
+impl ListParams {
+    fn matches(&self, journey: &Journey) -> bool {
+        self.min_delay
+            .is_none_or(|minutes| journey.recorded_delay_seconds() >= minutes.saturating_mul(60))
+            && self
+                .data_source
+                .as_ref()
+                .is_none_or(|ds| ds == journey.data_source())
+            && self.line.as_ref().is_none_or(|line| journey.on_line(line))
+            && self
+                .cancelled
+                .is_none_or(|cancelled| cancelled == journey.is_cancelled())
+            && self.stuck.is_none_or(|stuck| stuck == journey.overdue())
+    }
+
+    /// Filter and sort `journeys`, but don't paginate
+    pub fn select<'a>(&self, journeys: Vec<&'a Journey>) -> Vec<&'a Journey> {
+        let mut journeys: Vec<_> = journeys.into_iter().filter(|j| self.matches(j)).collect();
+        let order = |a: &&Journey, b: &&Journey| match self.sort {
+            None => {
+                let key = |j: &Journey| Reverse((j.overdue(), j.recorded_delay_seconds()));
+                key(a).cmp(&key(b))
+            }
+            Some(sort) => {
+                let order = match sort.key {
+                    SortKey::Delay => a.recorded_delay_seconds().cmp(&b.recorded_delay_seconds()),
+                    SortKey::Line => a.line_ref().cmp(b.line_ref()),
+                    SortKey::Stop => a.prev_stop_name().cmp(b.prev_stop_name()),
+                    SortKey::Time => a.prev_stop_actual_time().cmp(&b.prev_stop_actual_time()),
+                };
+                if sort.descending {
+                    order.reverse()
+                } else {
+                    order
+                }
+            }
+        };
+        // The same journeys come out in the same order, so that pages don't overlap
+        journeys.sort_by(|a, b| order(a, b).then_with(|| a.id().cmp(b.id())));
+        journeys
+    }
+
+    pub fn page<T>(&self, items: Vec<T>) -> Vec<T> {
+        items
+            .into_iter()
+            .skip(self.offset.unwrap_or(0))
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// Links to the previous and next page, if there is a limit and there are more items
+    pub fn neighbours(&self, total: usize) -> (Option<ListParams>, Option<ListParams>) {
+        let Some(limit) = self.limit else {
+            return (None, None);
+        };
+        let offset = self.offset.unwrap_or(0);
+        let prev = (offset > 0).then(|| ListParams {
+            offset: Some(offset.saturating_sub(limit)),
+            ..self.clone()
+        });
+        let next = offset
+            .checked_add(limit)
+            .filter(|next| *next < total)
+            .map(|next| ListParams {
+                offset: Some(next),
+                ..self.clone()
+            });
+        (prev, next)
+    }
+
+    pub fn sort_name(&self) -> String {
+        self.sort.map(|sort| sort.to_string()).unwrap_or_default()
+    }
+
+    pub fn query_string(&self) -> String {
+        serde_urlencoded::to_string(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use chrono::TimeDelta;
+
+    fn parse(query: &str) -> Result<ListParams, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_str(query)
+    }
+
+    #[test]
+    fn rejects_empty_pages() {
+        assert!(parse("limit=0").is_err());
+        assert_eq!(parse("limit=").unwrap(), ListParams::default());
+        assert_eq!(parse("limit=1").unwrap().limit, Some(1));
+    }
+
+    #[test]
+    fn links_to_neighbouring_pages() {
+        let params = parse("line=R10&limit=10&offset=10").unwrap();
+        let (prev, next) = params.neighbours(25);
+        let (prev, next) = (prev.unwrap(), next.unwrap());
+        assert_eq!(prev.offset, Some(0));
+        assert_eq!(next.offset, Some(20));
+        assert_eq!(next.line.as_deref(), Some("R10"));
+        assert_eq!(next.neighbours(25).1, None);
+
+        // Not past the last item, nor before the first
+        assert_eq!(params.neighbours(20).1, None);
+        let params = parse("limit=10&offset=5").unwrap();
+        assert_eq!(params.neighbours(25).0.unwrap().offset, Some(0));
+        assert_eq!(params.neighbours(25).1.unwrap().offset, Some(15));
+        assert_eq!(parse("limit=10").unwrap().neighbours(25).0, None);
+        // All in one page
+        assert_eq!(parse("offset=10").unwrap().neighbours(25), (None, None));
+    }
+
+    #[test]
+    fn neighbours_dont_overflow() {
+        let params = ListParams {
+            limit: Some(usize::MAX),
+            offset: Some(10),
+            ..ListParams::default()
+        };
+        let (prev, next) = params.neighbours(usize::MAX);
+        assert_eq!(prev.unwrap().offset, Some(0));
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn selects_and_sorts_journeys() {
+        let now = testing::minutes_from_now(0);
+        let journey = |line: &str, delay_minutes: i64| {
+            testing::source(
+                line,
+                vec![testing::visited(
+                    "Oslo S",
+                    now - TimeDelta::minutes(5),
+                    TimeDelta::minutes(delay_minutes),
+                )],
+                vec![testing::call("Lillestrøm", now + TimeDelta::minutes(10))],
+            )
+        };
+        let journeys = testing::journeys([
+            ("a", journey("VYG:Line:R10", 2)),
+            ("b", journey("VYG:Line:L1", 7)),
+            ("c", journey("GOA:Line:R10", 0)),
+            ("d", journey("VYG:Line:R10", 7)),
+        ]);
+        let select = |query: &str| -> Vec<String> {
+            parse(query)
+                .unwrap()
+                .select(journeys.iter().collect())
+                .into_iter()
+                .map(|journey| journey.id().to_string())
+                .collect()
+        };
+
+        // Most delayed first, then by id
+        assert_eq!(select(""), ["b", "d", "a", "c"]);
+        assert_eq!(select("sort=delay"), ["c", "a", "b", "d"]);
+        assert_eq!(select("sort=-line"), ["a", "d", "b", "c"]);
+        assert_eq!(select("line=R10&min_delay=1"), ["d", "a"]);
+        assert_eq!(select("data_source=VYG&line=VYG:Line:R10"), ["d", "a"]);
+        assert_eq!(select("cancelled=true"), Vec::<String>::new());
+        assert_eq!(select("stuck=false&min_delay=5"), ["b", "d"]);
+    }
+}
@@ -0,0 +1,332 @@
+// Retries, circuit breaking and a history of how fetching from upstream went
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+/// Why a fetch failed
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchErrorKind {
+    Timeout,
+    /// Unable to connect to upstream
+    Connect,
+    /// Upstream answered with an error status
+    HttpStatus,
+    /// The response wasn't valid SIRI
+    Decode,
+    /// Unable to read a static data file
+    Io,
+    Other,
+}
+
+/// The HTTP error behind `error`, if any. We read responses while parsing them, so these can be
+/// wrapped in io and JSON errors.
+fn http_error(error: &anyhow::Error) -> Option<&reqwest::Error> {
+    error.chain().find_map(|cause| {
+        cause.downcast_ref::<reqwest::Error>().or_else(|| {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(|io| io.get_ref())
+                .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+        })
+    })
+}
+
+impl FetchErrorKind {
+    pub fn classify(error: &anyhow::Error) -> Self {
+        if let Some(error) = http_error(error) {
+            if error.is_timeout() {
+                FetchErrorKind::Timeout
+            } else if error.is_connect() {
+                FetchErrorKind::Connect
+            } else if error.is_status() {
+                FetchErrorKind::HttpStatus
+            } else if error.is_decode() {
+                FetchErrorKind::Decode
+            } else {
+                FetchErrorKind::Other
+            }
+        } else if let Some(error) = error.downcast_ref::<serde_json::Error>() {
+            // We read while parsing, so this can also be an error reading a file
+            if error.is_io() {
+                FetchErrorKind::Io
+            } else {
+                FetchErrorKind::Decode
+            }
+        } else if error.downcast_ref::<std::io::Error>().is_some() {
+            FetchErrorKind::Io
+        } else {
+            FetchErrorKind::Other
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FetchErrorKind::Timeout => "timeout",
+            FetchErrorKind::Connect => "connect",
+            FetchErrorKind::HttpStatus => "http_status",
+            FetchErrorKind::Decode => "decode",
+            FetchErrorKind::Io => "io",
+            FetchErrorKind::Other => "other",
+        }
+    }
+}
+
+/// Whether trying again soon could help. Client errors, except for rate limiting, and documents
+/// we can't decode are unlikely to go away by themselves.
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    match FetchErrorKind::classify(error) {
+        FetchErrorKind::Timeout | FetchErrorKind::Connect | FetchErrorKind::Other => true,
+        FetchErrorKind::HttpStatus => http_error(error)
+            .and_then(|error| error.status())
+            .is_some_and(|status| status.is_server_error() || status.as_u16() == 429),
+        FetchErrorKind::Decode | FetchErrorKind::Io => false,
+    }
+}
+
+/// How many times to try a fetch, and how long to wait in between
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff, with jitter so that many instances don't retry in lockstep. The delay
+    /// after `attempt` (counting from 1) is somewhere between half of and the full backoff.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        backoff.mul_f64(rand::random_range(0.5..=1.0))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Fetching as usual
+    Closed,
+    /// Too many failures in a row, we don't fetch until the cooldown is over
+    Open,
+    /// The cooldown is over, and the next fetch decides whether to close or open again
+    HalfOpen,
+}
+
+/// Stops us from hammering upstream when it fails repeatedly
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            consecutive_failures: 0,
+            opened_at: None,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match self.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether we should fetch now
+    pub fn allow(&self) -> bool {
+        self.state() != CircuitState::Open
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        if self.opened_at.is_some() || self.consecutive_failures >= self.failure_threshold {
+            self.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+/// A single attempt at fetching from upstream
+#[derive(Clone, Serialize, ToSchema)]
+pub struct FetchAttempt {
+    pub at: DateTime<Utc>,
+    /// Counting from 1 within a fetch, higher numbers are retries
+    pub attempt: u32,
+    pub duration_ms: u64,
+    /// Unset when the attempt succeeded
+    pub error_kind: Option<FetchErrorKind>,
+    pub error: Option<String>,
+}
+
+/// Keep this many attempts in the history
+const HISTORY_LENGTH: usize = 20;
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct FetchStatus {
+    pub circuit: CircuitState,
+    /// Fetches that failed after all retries, since the last success
+    pub consecutive_failures: u32,
+    /// The most recent attempts, newest first
+    #[schema(value_type = Vec<FetchAttempt>)]
+    pub history: VecDeque<FetchAttempt>,
+}
+
+impl Default for FetchStatus {
+    fn default() -> Self {
+        Self {
+            circuit: CircuitState::Closed,
+            consecutive_failures: 0,
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+        }
+    }
+}
+
+impl FetchStatus {
+    pub fn record(&mut self, attempt: FetchAttempt) {
+        self.history.truncate(HISTORY_LENGTH - 1);
+        self.history.push_front(attempt);
+    }
+
+    pub fn observe_breaker(&mut self, breaker: &CircuitBreaker) {
+        self.circuit = breaker.state();
+        self.consecutive_failures = breaker.consecutive_failures;
+    }
+
+    /// The circuit breaker hasn't opened, or has closed again after a successful fetch
+    pub fn healthy(&self) -> bool {
+        self.circuit == CircuitState::Closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn opens_after_enough_failures_in_a_row() {
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(3600));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn half_open_after_the_cooldown_decides_on_the_next_fetch() {
+        let cooldown = Duration::from_millis(50);
+        let mut breaker = CircuitBreaker::new(2, cooldown);
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        sleep(cooldown * 2);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.allow());
+        // A single failure is enough to open again, and the cooldown starts over
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        sleep(cooldown * 2);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn unhealthy_until_a_fetch_succeeds_after_the_breaker_opened() {
+        let cooldown = Duration::from_millis(50);
+        let mut breaker = CircuitBreaker::new(2, cooldown);
+        let mut status = FetchStatus::default();
+        assert!(status.healthy());
+
+        breaker.record_failure();
+        status.observe_breaker(&breaker);
+        assert!(status.healthy());
+        assert_eq!(status.consecutive_failures, 1);
+        breaker.record_failure();
+        status.observe_breaker(&breaker);
+        assert!(!status.healthy());
+
+        // Still unhealthy when the cooldown is over, until the next fetch succeeds
+        sleep(cooldown * 2);
+        status.observe_breaker(&breaker);
+        assert_eq!(status.circuit, CircuitState::HalfOpen);
+        assert!(!status.healthy());
+        breaker.record_success();
+        status.observe_breaker(&breaker);
+        assert!(status.healthy());
+        assert_eq!(status.consecutive_failures, 0);
+    }
+
+    fn assert_delay_between(policy: &RetryPolicy, attempt: u32, min: Duration, max: Duration) {
+        for _ in 0..100 {
+            let delay = policy.delay(attempt);
+            assert!(
+                (min..=max).contains(&delay),
+                "attempt {attempt} waited {delay:?}, not between {min:?} and {max:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn delay_backs_off_exponentially_with_jitter() {
+        let policy = RetryPolicy {
+            attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+        assert_delay_between(
+            &policy,
+            1,
+            Duration::from_millis(50),
+            Duration::from_millis(100),
+        );
+        assert_delay_between(
+            &policy,
+            3,
+            Duration::from_millis(200),
+            Duration::from_millis(400),
+        );
+    }
+
+    #[test]
+    fn delay_saturates_at_max_delay() {
+        let policy = RetryPolicy {
+            attempts: u32::MAX,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+        for attempt in [5, 32, 64, u32::MAX] {
+            assert_delay_between(
+                &policy,
+                attempt,
+                Duration::from_millis(500),
+                Duration::from_secs(1),
+            );
+        }
+    }
+}
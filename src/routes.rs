@@ -1,8 +1,10 @@
 // Router setup
 use crate::handlers;
+use crate::server::infra;
 use crate::server::state::AppState;
 use axum::error_handling::HandleErrorLayer;
 use axum::http::StatusCode;
+use axum::middleware;
 use axum::routing::get;
 use axum::{Router, http};
 use http::HeaderValue;
@@ -15,16 +17,80 @@ use tower_http::cors::CorsLayer;
 use tower_http::services::ServeDir;
 use tower_http::set_header::SetResponseHeaderLayer;
 use tracing::error;
+use utoipa::OpenApi;
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+use utoipa_swagger_ui::{Config, SwaggerUi};
+
+#[derive(OpenApi)]
+#[openapi(tags(
+    (name = "journeys", description = "Journeys currently in traffic"),
+    (name = "feeds", description = "Atom feeds of disruptions"),
+    (name = "stops", description = "Stop reference data"),
+    (name = "operations", description = "Health and metrics"),
+))]
+struct ApiDoc;
+
+/// Version 1 of the JSON API. When the response types need to change in ways that would break
+/// consumers, add the new ones in `api::v2` and nest them under `/api/v2` next to this.
+fn api_v1_routes() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(handlers::by_stop_name))
+        .routes(routes!(handlers::stop_names))
+        .routes(routes!(handlers::search_stops))
+        .routes(routes!(handlers::stop_area))
+        .routes(routes!(handlers::by_stop_area))
+        .routes(routes!(handlers::train_journeys))
+        .routes(routes!(handlers::journey))
+        .routes(routes!(handlers::quality))
+        .routes(routes!(handlers::unresolved_stops))
+}
+
+/// The routes that make up the documented API, everything else is HTML or static assets.
+fn api_routes() -> OpenApiRouter<AppState> {
+    OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .nest("/api/v1", api_v1_routes())
+        .routes(routes!(handlers::healthy))
+        .routes(routes!(handlers::livez))
+        .routes(routes!(handlers::readyz))
+        .routes(routes!(handlers::metrics))
+        .routes(routes!(handlers::stop_feed))
+        .routes(routes!(handlers::line_feed))
+        .routes(routes!(handlers::trains_feed))
+}
+
+pub fn openapi() -> utoipa::openapi::OpenApi {
+    api_routes().into_openapi()
+}
 
 pub fn create_router(state: AppState) -> Router {
+    let (api, openapi) = api_routes().split_for_parts();
     Router::new()
         .route("/", get(handlers::root))
-        .route("/healthy", get(handlers::healthy))
+        .route("/trains.html", get(handlers::train_journeys_html))
+        .route("/journey/{id}", get(handlers::journey_html))
+        .route("/quality", get(handlers::quality_redirect))
+        .route("/quality.html", get(handlers::quality_html))
+        // The JSON API used to live at the root, these are kept for existing consumers
         .route("/stop/{stop_name}", get(handlers::by_stop_name))
         .route("/stops", get(handlers::stop_names))
         .route("/trains", get(handlers::train_journeys))
-        .route("/trains.html", get(handlers::train_journeys_html))
+        .merge(api)
+        .merge(
+            SwaggerUi::new("/docs")
+                .url("/openapi.json", openapi)
+                // Relative to /docs/, so that it works behind a proxy that serves us under a prefix
+                .config(Config::new(["../openapi.json"])),
+        )
         .nest_service("/static", ServeDir::new("static"))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            infra::until_loaded,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            infra::track_http_requests,
+        ))
         .layer(
             ServiceBuilder::new()
                 .layer(HandleErrorLayer::new(|_: BoxError| async {
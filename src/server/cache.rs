@@ -0,0 +1,180 @@
+// Response bodies that only change with the state, prepared once per fetch instead of per request
+use crate::api::TrainsPage;
+use crate::api::v1::TrainJourney;
+use crate::membased::Journeys;
+use crate::params::ListParams;
+use askama::Template;
+use axum::body::Bytes;
+use axum::http::header::{
+    ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY,
+};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// How often the state job prepares the responses again. Besides the journeys, they depend on the
+/// time, through how long journeys have been stuck and the predictions.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Older responses are built per request instead, eg. while the state job is busy reloading stops
+const MAX_AGE: Duration = Duration::from_secs(45);
+
+/// A body and its gzipped version, each with a strong ETag
+pub struct CachedBody {
+    content_type: HeaderValue,
+    plain: Bytes,
+    gzip: Bytes,
+    etag: HeaderValue,
+    gzip_etag: HeaderValue,
+}
+
+impl CachedBody {
+    pub fn new(content_type: &'static str, body: Vec<u8>) -> anyhow::Result<Self> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body)?;
+        let gzip = encoder.finish()?;
+        // The same body gets the same ETag, also after a restart
+        let hash = fxhash::hash64(body.as_slice());
+        Ok(Self {
+            content_type: HeaderValue::from_static(content_type),
+            plain: Bytes::from(body),
+            gzip: Bytes::from(gzip),
+            etag: HeaderValue::from_str(&format!("\"{hash:016x}\""))?,
+            gzip_etag: HeaderValue::from_str(&format!("\"{hash:016x}-gzip\""))?,
+        })
+    }
+
+    /// Gzipped if `request` accepts it, or 304 Not Modified if the client already has this version
+    pub fn respond(&self, request: &HeaderMap) -> Response {
+        let gzip = accepts_gzip(request);
+        let etag = if gzip { &self.gzip_etag } else { &self.etag };
+        let headers = [
+            (ETAG, etag.clone()),
+            (VARY, HeaderValue::from_static("accept-encoding")),
+        ];
+        if matches_etag(request, etag) {
+            return (StatusCode::NOT_MODIFIED, headers).into_response();
+        }
+        let (body, encoding) = if gzip {
+            (self.gzip.clone(), Some(HeaderValue::from_static("gzip")))
+        } else {
+            (self.plain.clone(), None)
+        };
+        let mut response =
+            (headers, [(CONTENT_TYPE, self.content_type.clone())], body).into_response();
+        if let Some(encoding) = encoding {
+            response.headers_mut().insert(CONTENT_ENCODING, encoding);
+        }
+        response
+    }
+}
+
+/// Accept-Encoding lists gzip, without q=0
+fn accepts_gzip(request: &HeaderMap) -> bool {
+    request
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|param| {
+                param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            });
+            name.eq_ignore_ascii_case("gzip") && !refused
+        })
+}
+
+/// If-None-Match lists `etag`, compared weakly like RFC 9110 says we should
+fn matches_etag(request: &HeaderMap, etag: &HeaderValue) -> bool {
+    request
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|candidate| {
+            candidate == "*" || candidate.trim_start_matches("W/").as_bytes() == etag.as_bytes()
+        })
+}
+
+/// The responses to requests without query parameters, which is most of them
+pub struct CachedResponses {
+    pub trains: CachedBody,
+    /// For x-total-count
+    pub trains_total: usize,
+    pub trains_html: CachedBody,
+    prepared_at: Instant,
+}
+
+impl CachedResponses {
+    pub fn new(journeys: &Journeys, assets_path: &str) -> anyhow::Result<Self> {
+        let params = ListParams::default();
+        let trains: Vec<TrainJourney> = params
+            .select(journeys.train_journeys())
+            .into_iter()
+            .map(TrainJourney::from)
+            .collect();
+        let trains_total = trains.len();
+        let json = serde_json::to_vec(&trains)?;
+        let html = TrainsPage::new(trains, params, assets_path.to_string()).render()?;
+        Ok(Self {
+            trains: CachedBody::new("application/json", json)?,
+            trains_total,
+            trains_html: CachedBody::new("text/html; charset=utf-8", html.into_bytes())?,
+            prepared_at: Instant::now(),
+        })
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.prepared_at.elapsed() < MAX_AGE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: axum::http::HeaderName, values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn accepts_gzip_unless_refused() {
+        let accepts = |values: &[&'static str]| accepts_gzip(&headers(ACCEPT_ENCODING, values));
+        assert!(accepts(&["gzip"]));
+        assert!(accepts(&["GZip"]));
+        assert!(accepts(&["br, gzip;q=0.8, deflate"]));
+        assert!(accepts(&["br", "gzip"]));
+        assert!(accepts(&["gzip ; q=0.5"]));
+        assert!(!accepts(&[]));
+        assert!(!accepts(&["br, deflate"]));
+        assert!(!accepts(&["gzip;q=0"]));
+        assert!(!accepts(&["gzip;q=0.0, br"]));
+        assert!(!accepts(&["x-gzip"]));
+    }
+
+    #[test]
+    fn matches_etag_weakly_in_lists_and_wildcards() {
+        let etag = HeaderValue::from_static("\"0123456789abcdef\"");
+        let matches =
+            |values: &[&'static str]| matches_etag(&headers(IF_NONE_MATCH, values), &etag);
+        assert!(matches(&["\"0123456789abcdef\""]));
+        assert!(matches(&["W/\"0123456789abcdef\""]));
+        assert!(matches(&["\"other\", \"0123456789abcdef\""]));
+        assert!(matches(&["\"other\"", "W/\"0123456789abcdef\""]));
+        assert!(matches(&["*"]));
+        assert!(!matches(&[]));
+        assert!(!matches(&["\"other\""]));
+        assert!(!matches(&["\"0123456789abcdef-gzip\""]));
+        assert!(!matches(&["0123456789abcdef"]));
+    }
+}
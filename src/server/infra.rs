@@ -1,8 +1,14 @@
 // Infrastructure concerns: error handling, signals, response types
-use crate::api::TrainsPage;
+use crate::api::{AtomFeed, JourneyPage, LoadingPage, QualityPage, TrainsPage};
+use crate::server::state::AppState;
 use askama::Template;
+use axum::extract::{MatchedPath, Request, State};
 use axum::http::StatusCode;
+use axum::http::header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE, RETRY_AFTER};
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 use tokio::signal;
 use tokio::sync::watch::Sender;
 use tracing::{error, info};
@@ -48,25 +54,115 @@ const TEMPLATE_ERROR_HTML: &str = r#"<!DOCTYPE html>
     <div class="error">
         <h1>⚠️ En ukjent feil oppsto</h1>
         <p>Beklager, vi kunne ikke vise siden.</p>
-        <p><a href="/trains">Prøv JSON API</a> eller <a href="/">tilbake til forsiden</a></p>
+        <p><a href="/api/v1/trains">Prøv JSON API</a> eller <a href="/">tilbake til forsiden</a></p>
     </div>
 </body>
 </html>"#;
 
+fn html_response(rendered: askama::Result<String>) -> Response {
+    if let Ok(html) = rendered {
+        axum::response::Html(html).into_response()
+    } else {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            axum::response::Html(TEMPLATE_ERROR_HTML),
+        )
+            .into_response()
+    }
+}
+
 impl IntoResponse for TrainsPage {
     fn into_response(self) -> Response {
-        if let Ok(html) = self.render() {
-            axum::response::Html(html).into_response()
-        } else {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                axum::response::Html(TEMPLATE_ERROR_HTML),
-            )
-                .into_response()
+        html_response(self.render())
+    }
+}
+
+impl IntoResponse for JourneyPage {
+    fn into_response(self) -> Response {
+        html_response(self.render())
+    }
+}
+
+impl IntoResponse for QualityPage {
+    fn into_response(self) -> Response {
+        html_response(self.render())
+    }
+}
+
+impl IntoResponse for LoadingPage {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, "5"), (CACHE_CONTROL, "no-store")],
+            html_response(self.render()),
+        )
+            .into_response()
+    }
+}
+
+impl IntoResponse for AtomFeed {
+    fn into_response(self) -> Response {
+        match self.render() {
+            Ok(xml) => {
+                ([(CONTENT_TYPE, "application/atom+xml; charset=utf-8")], xml).into_response()
+            }
+            Err(reason) => WebappError::from(reason).into_response(),
         }
     }
 }
 
+/// These work before the initial import is done
+const AVAILABLE_WHILE_LOADING: &[&str] = &["/livez", "/readyz", "/healthy", "/metrics"];
+
+/// Answer 503 until the initial import is done, with a page that reloads itself for browsers
+pub async fn until_loaded(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if state.loaded.load(Ordering::Acquire)
+        || AVAILABLE_WHILE_LOADING.contains(&path)
+        || path.starts_with("/static/")
+    {
+        return next.run(request).await;
+    }
+    let wants_html = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    if wants_html {
+        LoadingPage {
+            assets_path: state.assets_path.clone(),
+        }
+        .into_response()
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, "5"), (CACHE_CONTROL, "no-store")],
+            "Still loading data, try again shortly",
+        )
+            .into_response()
+    }
+}
+
+pub async fn track_http_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    state
+        .metrics
+        .http_requests
+        .with_label_values(&[route.as_str(), response.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
 pub async fn shutdown_signal(terminate_jobs: Sender<bool>) {
     let interrupt = async {
         signal::ctrl_c()
@@ -1,133 +1,392 @@
 // Application state management and background jobs
+use crate::alerts::Alerts;
+use crate::cli::SharedOptions;
 use crate::db;
-use crate::entur_data::{self, Config};
-use crate::entur_siriformat::SiriETResponse;
-use crate::membased::{Journeys, Stops};
-use chrono::{Duration, Utc};
+use crate::feeds::{Batch, Feed};
+use crate::ingest::Parsed;
+use crate::membased::{Journeys, Stops, UnresolvedStops};
+use crate::metrics::Metrics;
+use crate::resilience::{
+    CircuitBreaker, FetchAttempt, FetchErrorKind, FetchStatus, RetryPolicy, is_retryable,
+};
+use crate::server::cache::{self, CachedResponses};
+use crate::snapshot::{self, SnapshotConfig};
+use crate::stop_source::StopSource;
+use crate::stuck::StuckConfig;
+use anyhow::bail;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use chrono::{DateTime, Duration, FixedOffset, TimeDelta, Utc};
 use chrono_tz::Europe::Oslo;
 use duckdb::Connection;
-use reqwest::ClientBuilder;
+use reqwest::{Client, ClientBuilder};
+use std::collections::BTreeMap;
 use std::ops::Sub;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 use std::time;
+use tokio::sync::mpsc;
 use tokio::sync::watch::Receiver;
-use tracing::{error, info};
-use uuid::Uuid;
+use tracing::{error, info, warn};
 
+/// The journeys and stops are immutable snapshots that we replace as a whole, so that readers
+/// never wait for the state job, and a panic there can't take serving down with it.
 #[derive(Clone)]
 pub struct AppState {
-    pub state: Arc<RwLock<Journeys>>,
-    pub stops: Arc<Stops>,
-    pub last_successful_sync: Arc<RwLock<u32>>,
-    pub next_sync: Arc<RwLock<u32>>,
+    pub state: Arc<ArcSwap<Journeys>>,
+    /// Prepared from `state` whenever it's replaced, unset if that failed
+    pub responses: Arc<ArcSwapOption<CachedResponses>>,
+    /// The initial import is done, until then we serve a loading page
+    pub loaded: Arc<AtomicBool>,
+    /// Data sources with older data than this aren't fresh, unset when we don't fetch periodically
+    pub max_data_age: Option<TimeDelta>,
+    /// Replaced as a whole when the stop registry is reloaded
+    pub stops: Arc<ArcSwap<Stops>>,
+    pub last_successful_sync: Arc<AtomicU32>,
+    /// The stops were read from the parquet cache, because --parquet-root was unavailable
+    pub stops_from_cache: Arc<AtomicBool>,
+    pub next_sync: Arc<AtomicU32>,
+    /// By feed name
+    pub fetch_status: Arc<RwLock<BTreeMap<String, FetchStatus>>>,
+    pub metrics: Arc<Metrics>,
+    pub stuck: Arc<StuckConfig>,
+    pub log_unresolved_stops: bool,
     pub assets_path: String,
 }
 
-pub async fn initial_import(
-    requestor_id: Option<String>,
-    api_url: String,
-    static_data: Option<String>,
-    db_url: &Option<String>,
-    parquet_root: &str,
-    threads: u8,
-    memory_gb: u8,
-) -> anyhow::Result<(Connection, SiriETResponse, Config)> {
-    let me = requestor_id.unwrap_or_else(|| Uuid::new_v4().to_string());
-
-    let client = ClientBuilder::default()
+impl AppState {
+    pub fn observe_breaker(&self, feed: &str, breaker: &CircuitBreaker) {
+        // A writer that panicked left at most one attempt unrecorded, so keep recording
+        self.fetch_status
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(feed.to_string())
+            .or_default()
+            .observe_breaker(breaker);
+    }
+}
+
+/// What we need from booting to serve and keep the state fresh
+pub struct InitialImport {
+    /// With the stop registry
+    pub db: Connection,
+    pub stop_source: StopSource,
+    pub stops_from_cache: bool,
+}
+
+/// The client we use for everything we fetch
+pub fn http_client() -> anyhow::Result<Client> {
+    Ok(ClientBuilder::default()
         .connect_timeout(time::Duration::from_millis(1_000))
         .timeout(time::Duration::from_millis(60_000))
-        .build()?;
+        .build()?)
+}
 
-    let db = db::prepare_db(db_url, parquet_root, threads, memory_gb)?;
-    let config = Config::new(me, api_url, client, static_data);
+/// Import the stop registry. Fetching journeys is up to the feeds, and we may start from a
+/// snapshot instead.
+pub async fn initial_import(
+    options: SharedOptions,
+    client: Client,
+) -> anyhow::Result<InitialImport> {
+    let stop_source = StopSource::new(options.parquet_root, options.parquet_cache_dir, client);
+    if let Some(seed) = &options.seed_parquet_cache {
+        stop_source.seed(seed)?;
+    }
+    let prepared = stop_source.prepare().await?;
+    let db = db::prepare_db(
+        &options.db_url,
+        &prepared.root,
+        options.threads,
+        options.memory_gb,
+    )?;
+    Ok(InitialImport {
+        db,
+        stop_source,
+        stops_from_cache: prepared.from_cache,
+    })
+}
 
-    let data = entur_data::fetch_data(&config).await?;
-    Ok((db, data, config))
+/// Replace the journeys, and prepare the responses that only depend on them. If we can't, the
+/// handlers build those per request instead.
+pub fn publish(state: &AppState, journeys: Journeys) {
+    let responses = prepare_responses(&journeys, &state.assets_path);
+    state.state.store(Arc::new(journeys));
+    state.responses.store(responses);
+}
+
+/// Prepare the responses again from the journeys we have, since they also depend on the time
+pub fn refresh_responses(state: &AppState) {
+    let responses = prepare_responses(&state.state.load(), &state.assets_path);
+    state.responses.store(responses);
+}
+
+fn prepare_responses(journeys: &Journeys, assets_path: &str) -> Option<Arc<CachedResponses>> {
+    match CachedResponses::new(journeys, assets_path) {
+        Ok(responses) => Some(Arc::new(responses)),
+        Err(reason) => {
+            error!("Unable to prepare responses: {reason:?}");
+            None
+        }
+    }
+}
+
+/// Journeys that haven't been updated since this are expired
+pub fn expiry_cutoff() -> DateTime<FixedOffset> {
+    Utc::now()
+        .with_timezone(&Oslo)
+        .sub(Duration::hours(1))
+        .fixed_offset()
+}
+
+/// Fetch, and retry according to `policy`, recording every attempt. Gives up right away on errors
+/// that retrying won't fix, and when we're shutting down.
+pub async fn fetch_with_retry(
+    feed: &Feed,
+    policy: &RetryPolicy,
+    state: &AppState,
+    shutdown: &mut Receiver<bool>,
+) -> anyhow::Result<Parsed> {
+    let name = feed.name.as_ref();
+    let mut attempt = 1;
+    loop {
+        let at = Utc::now();
+        let started = time::Instant::now();
+        let result = feed.fetch(state).await;
+        let error_kind = result.as_ref().err().map(FetchErrorKind::classify);
+        if let Some(kind) = error_kind {
+            state
+                .metrics
+                .fetch_errors
+                .with_label_values(&[name, kind.as_str()])
+                .inc();
+        }
+        state
+            .fetch_status
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(name.to_string())
+            .or_default()
+            .record(FetchAttempt {
+                at,
+                attempt,
+                duration_ms: started.elapsed().as_millis() as u64,
+                error_kind,
+                error: result.as_ref().err().map(|reason| format!("{reason:#}")),
+            });
+        match result {
+            Ok(data) => return Ok(data),
+            Err(reason) if attempt >= policy.attempts || !is_retryable(&reason) => {
+                return Err(reason);
+            }
+            Err(reason) => {
+                let delay = policy.delay(attempt);
+                warn!(
+                    "Fetch attempt {attempt} of {name} failed, retrying in {delay:?}: {reason:#}"
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    // The only change is the signal to shut down
+                    _ = shutdown.changed() => return Err(reason.context("Shutting down")),
+                }
+                attempt += 1;
+            }
+        }
+    }
 }
 
 #[tracing::instrument(name = "replace_state", skip_all)]
-pub fn replace_state(siri: anyhow::Result<SiriETResponse>, state: AppState) -> anyhow::Result<()> {
-    // PoisonError can _only_ happen when a thread panics while holding an exclusive lock.
-    // this fn is the only place that takes this exclusive lock, and only to swap the content of it.
-    // If that happens, I don't have a better idea than panicing anyway, other than maybe try to shut
-    // down the whole process.
-    let version = *state.next_sync.read().unwrap();
-    let new_journeys = Journeys::new(&state.stops, siri?.journeys());
+pub fn replace_state(batch: Batch, state: &AppState) {
+    let version = state.next_sync.load(Ordering::Relaxed);
+    let new_journeys = batch.journeys;
     let updated = new_journeys.len();
-    // PoisonError again, which we can't handle.
-    // We clone to avoid holding a write-lock for any operations other than swapping
-    // the state out. This way, we can update `old_journeys`, then just move it into the state as
-    // soon as nobody is reading it anymore. Scope to ensure we drop the lock immediately after cloning.
-    let mut old_journeys = { state.state.read().unwrap().clone() };
+    if state.log_unresolved_stops {
+        log_unresolved_stops(new_journeys.unresolved_stops());
+    }
+    // Readers keep the snapshot they have until they're done with it, while we build the next one
+    // from a copy. Only the state job replaces the journeys, so nothing changes under us.
+    let mut old_journeys = Journeys::clone(&state.state.load());
     let old = old_journeys.len();
-    let cutoff = Utc::now()
-        .with_timezone(&Oslo)
-        .sub(Duration::hours(1))
-        .fixed_offset();
-    old_journeys.expire(cutoff);
+    old_journeys.expire(expiry_cutoff());
     let expired = old - old_journeys.len();
     old_journeys.merge_from(new_journeys);
+    old_journeys.link_replacements();
     let resulting = old_journeys.len();
+    state.metrics.observe_state(&old_journeys);
+    state.metrics.updated.inc_by(updated as u64);
+    state.metrics.expired.inc_by(expired as u64);
 
-    // Scope to drop the lock immediately after swapping
-    {
-        // PoisonError. Immediately get rid of this lock.
-        *state.state.write().unwrap() = old_journeys;
-    }
+    publish(state, old_journeys);
     // We synced successfully, let's tell the health check
-    {
-        *state.last_successful_sync.write().unwrap() = version
+    state.last_successful_sync.store(version, Ordering::Relaxed);
+    state.next_sync.fetch_add(1, Ordering::Relaxed);
+    info!(
+        "feed={} had={old} updated={updated} expired={expired} resulting={resulting} journeys.",
+        batch.feed
+    );
+}
+
+/// Log a single line with the StopPointRefs we couldn't find in one batch of journeys
+pub fn log_unresolved_stops(unresolved: &UnresolvedStops) {
+    if unresolved.is_empty() {
+        return;
     }
-    {
-        *state.next_sync.write().unwrap() += 1
+    let refs = unresolved.by_count();
+    let listed: Vec<_> = refs
+        .iter()
+        .take(20)
+        .map(|(stop_point_ref, stop)| match &stop.fallback_name {
+            Some(name) => format!("{stop_point_ref} ({name}) x{}", stop.calls),
+            None => format!("{stop_point_ref} (dropped) x{}", stop.calls),
+        })
+        .collect();
+    warn!(
+        "unresolved={} without_ref={} stop point refs: {}",
+        refs.len(),
+        unresolved.calls_without_ref,
+        listed.join(", ")
+    );
+}
+
+/// Where to reload the stop registry from, and how often
+pub struct StopRefresh {
+    pub interval: time::Duration,
+    pub source: StopSource,
+    pub db: Arc<Mutex<Connection>>,
+}
+
+/// Reload the stop registry, then resolve the stops of every journey in state again. Leaves the
+/// state alone if anything fails.
+#[tracing::instrument(name = "refresh_stops", skip_all)]
+pub async fn refresh_stops(refresh: &StopRefresh, state: &AppState) -> anyhow::Result<()> {
+    let prepared = refresh.source.prepare().await?;
+    let db = refresh.db.clone();
+    let root = prepared.root.clone();
+    let stops = tokio::task::spawn_blocking(move || -> anyhow::Result<Stops> {
+        // PoisonError can only happen if an earlier refresh panicked, and then we can't trust the db
+        let mut db = db.lock().unwrap();
+        db::load_stop_data(&mut db, &root)?;
+        Ok(Stops::new(db::read_stops(&db)?, db::read_stop_places(&db)?))
+    })
+    .await??;
+    if stops.is_empty() {
+        bail!("No stops in {}, keeping the ones we have", prepared.root);
     }
-    info!("had={old} updated={updated} expired={expired} resulting={resulting} journeys.");
+    let mut journeys = Journeys::clone(&state.state.load());
+    journeys.resolve(&stops, &state.stuck);
+    journeys.link_replacements();
+    let quays = stops.len();
+    // Swap the stops first, so that a fetch after this resolves against the new ones
+    state.stops.store(Arc::new(stops));
+    state.metrics.observe_state(&journeys);
+    publish(state, journeys);
+    state
+        .stops_from_cache
+        .store(prepared.from_cache, Ordering::Relaxed);
+    info!("Reloaded {quays} quays from {}", prepared.root);
     Ok(())
 }
 
-pub fn set_up_fetch_job(
-    fetch_interval_seconds: Option<u16>,
+/// Save a snapshot of the state without blocking the runtime
+async fn save_snapshot(config: &SnapshotConfig, state: &AppState) {
+    let journeys = state.state.load_full();
+    let last_successful_sync = state.last_successful_sync.load(Ordering::Relaxed);
+    let next_sync = state.next_sync.load(Ordering::Relaxed);
+    let path = config.path.clone();
+    let saved = tokio::task::spawn_blocking(move || {
+        snapshot::save(&path, &journeys, last_successful_sync, next_sync)
+    })
+    .await;
+    match saved {
+        Ok(Err(reason)) => error!("Unable to save snapshot: {reason:?}"),
+        Err(reason) => error!("Unable to save snapshot: {reason:?}"),
+        Ok(Ok(())) => {}
+    }
+}
+
+/// The jobs that keep the state fresh
+pub struct BackgroundJobs {
+    /// The journeys the feeds fetch
+    pub batches: mpsc::Receiver<Batch>,
+    pub stop_refresh: Option<StopRefresh>,
+    pub snapshot: Option<SnapshotConfig>,
+}
+
+/// Merges what the feeds fetch, reloads stops and saves snapshots according to `jobs`. These
+/// happen in the same task, so that they don't overwrite each other's changes to the state.
+pub fn set_up_state_job(
+    jobs: BackgroundJobs,
     recv_shutdown: Receiver<bool>,
-    entur_config: Config,
+    alerts: Option<Alerts>,
     state: AppState,
-) -> Option<tokio::task::JoinHandle<()>> {
-    if let Some(interval_seconds) = fetch_interval_seconds {
-        let mut recv_shutdown = recv_shutdown.clone();
-        Some(tokio::spawn(async move {
-            let mut interval =
-                tokio::time::interval(time::Duration::from_secs(interval_seconds as u64));
+) -> tokio::task::JoinHandle<()> {
+    let BackgroundJobs {
+        mut batches,
+        stop_refresh,
+        snapshot,
+    } = jobs;
+    let mut recv_shutdown = recv_shutdown.clone();
+    tokio::spawn(async move {
+        // The intervals of disabled jobs are never polled
+        let mut stop_interval = tokio::time::interval(
+            stop_refresh
+                .as_ref()
+                .map(|refresh| refresh.interval)
+                .unwrap_or(time::Duration::from_secs(1)),
+        );
+        let mut snapshot_interval = tokio::time::interval(
+            snapshot
+                .as_ref()
+                .map(|snapshot| snapshot.interval)
+                .unwrap_or(time::Duration::from_secs(1)),
+        );
 
-            let mut first = true;
+        let mut responses_interval = tokio::time::interval(cache::REFRESH_INTERVAL);
 
-            loop {
-                tokio::select! {
-                    _ = interval.tick() => {
-                        if first {
-                            first = false;
-                            continue;
-                        }
-                        let r = replace_state(
-                            entur_data::fetch_data(&entur_config).await,
-                            state.clone()
-                        );
-
-                        if let Err(reason) = r {
-                            error!("Unable to replace state: {reason:?}");
-                        } else {
-                            info!("Replaced state successfully");
-                        }
+        // The first tick is immediate, skip it
+        let mut first_stops = true;
+        let mut first_snapshot = true;
+
+        loop {
+            tokio::select! {
+                // None once every feed is done, which only happens when none of them are periodic
+                Some(batch) = batches.recv() => {
+                    replace_state(batch, &state);
+                    if let Some(alerts) = alerts.as_ref() {
+                        alerts.deliver(alerts.evaluate(&state.state.load()));
+                    }
+                }
+                _ = stop_interval.tick(), if stop_refresh.is_some() => {
+                    // The stops were loaded when we booted
+                    if first_stops {
+                        first_stops = false;
+                        continue;
                     }
-                    _ = recv_shutdown.changed() => {
-                        if *recv_shutdown.borrow() {
-                            info!("Shutdown job");
-                            break;
+                    if let Some(refresh) = stop_refresh.as_ref()
+                        && let Err(reason) = refresh_stops(refresh, &state).await
+                    {
+                        error!("Unable to reload stops: {reason:?}");
+                    }
+                }
+                _ = responses_interval.tick() => refresh_responses(&state),
+                _ = snapshot_interval.tick(), if snapshot.is_some() => {
+                    // Nothing has changed since we booted
+                    if first_snapshot {
+                        first_snapshot = false;
+                        continue;
+                    }
+                    if let Some(snapshot) = snapshot.as_ref() {
+                        save_snapshot(snapshot, &state).await;
+                    }
+                }
+                _ = recv_shutdown.changed() => {
+                    if *recv_shutdown.borrow() {
+                        if let Some(snapshot) = snapshot.as_ref() {
+                            save_snapshot(snapshot, &state).await;
                         }
+                        info!("Shutdown job");
+                        break;
                     }
                 }
             }
-        }))
-    } else {
-        None
-    }
+        }
+    })
 }
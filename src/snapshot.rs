@@ -0,0 +1,209 @@
+// Snapshots of the state on disk, so that we can serve journeys right after a restart
+use crate::membased::{JourneySource, Journeys};
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Bump this when the content changes, older snapshots are then ignored
+const SNAPSHOT_VERSION: u32 = 3;
+
+/// Where to keep the snapshot, and how often to save it
+#[derive(Clone)]
+pub struct SnapshotConfig {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    saved_at: DateTime<FixedOffset>,
+    last_successful_sync: u32,
+    next_sync: u32,
+    journeys: Vec<SnapshotJourneyRef<'a>>,
+}
+
+#[derive(Serialize)]
+struct SnapshotJourneyRef<'a> {
+    feed: &'a str,
+    id: &'a str,
+    journey: &'a JourneySource,
+}
+
+/// What we kept of the journeys we received, so that they are resolved against the stops we have when
+/// restoring, along with the sync counters of the health check.
+#[derive(Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    pub saved_at: DateTime<FixedOffset>,
+    pub last_successful_sync: u32,
+    pub next_sync: u32,
+    pub journeys: Vec<SnapshotJourney>,
+}
+
+/// A journey, with its id and the name of the feed we received it from
+#[derive(Deserialize)]
+pub struct SnapshotJourney {
+    pub feed: String,
+    pub id: String,
+    pub journey: JourneySource,
+}
+
+/// Write the snapshot as zstd compressed JSON. We write to a temporary file first, so that a crash
+/// while saving leaves the previous snapshot intact.
+pub fn save(
+    path: &Path,
+    journeys: &Journeys,
+    last_successful_sync: u32,
+    next_sync: u32,
+) -> anyhow::Result<()> {
+    let snapshot = SnapshotRef {
+        version: SNAPSHOT_VERSION,
+        saved_at: Utc::now().fixed_offset(),
+        last_successful_sync,
+        next_sync,
+        journeys: journeys
+            .sources()
+            .map(|(feed, id, journey)| SnapshotJourneyRef { feed, id, journey })
+            .collect(),
+    };
+    let partial = path.with_extension("partial");
+    let mut encoder = zstd::Encoder::new(BufWriter::new(File::create(&partial)?), 3)?;
+    serde_json::to_writer(&mut encoder, &snapshot)?;
+    encoder.finish()?;
+    fs::rename(&partial, path)?;
+    info!(
+        "Saved {} journeys to {}",
+        snapshot.journeys.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Read the snapshot at `path`, if there is one we can use
+pub fn load(path: &Path) -> anyhow::Result<Option<Snapshot>> {
+    if !path.exists() {
+        info!("No snapshot at {}", path.display());
+        return Ok(None);
+    }
+    let decoder = zstd::Decoder::new(BufReader::new(File::open(path)?))?;
+    let snapshot: Snapshot = serde_json::from_reader(decoder)?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        warn!(
+            "Ignoring snapshot at {} with version {}, expected {SNAPSHOT_VERSION}",
+            path.display(),
+            snapshot.version
+        );
+        return Ok(None);
+    }
+    info!(
+        "Loaded {} journeys saved at {} from {}",
+        snapshot.journeys.len(),
+        snapshot.saved_at,
+        path.display()
+    );
+    Ok(Some(snapshot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::membased::Stops;
+    use crate::stuck::StuckConfig;
+    use crate::testing;
+    use chrono::TimeDelta;
+    use std::sync::Arc;
+
+    #[test]
+    fn restores_journeys_and_sync_counters() {
+        let dir = testing::TempDir::new("snapshot-round-trip");
+        let path = dir.path().join("snapshot.json.zst");
+        let start = testing::minutes_from_now(-5);
+        let journeys = testing::journeys([
+            (
+                "VYG:ServiceJourney:1",
+                testing::source(
+                    "VYG:Line:R10",
+                    vec![testing::visited("Oslo S", start, TimeDelta::minutes(3))],
+                    vec![testing::call("Lillestrøm", start + TimeDelta::minutes(10))],
+                ),
+            ),
+            (
+                "GOA:ServiceJourney:2",
+                testing::source(
+                    "GOA:Line:F6",
+                    vec![testing::visited("Kristiansand", start, TimeDelta::zero())],
+                    vec![testing::call("Egersund", start + TimeDelta::hours(2))],
+                ),
+            ),
+        ]);
+
+        save(&path, &journeys, 41, 42).unwrap();
+        assert!(!path.with_extension("partial").exists());
+        let snapshot = load(&path).unwrap().unwrap();
+        assert_eq!(snapshot.last_successful_sync, 41);
+        assert_eq!(snapshot.next_sync, 42);
+        assert_eq!(snapshot.journeys.len(), 2);
+        assert!(
+            snapshot
+                .journeys
+                .iter()
+                .all(|journey| journey.feed == "test")
+        );
+
+        let stops = Stops::new(Vec::new(), Vec::new());
+        let restored = Journeys::restore(
+            &stops,
+            &StuckConfig::default(),
+            snapshot
+                .journeys
+                .into_iter()
+                .map(|journey| (Arc::from(journey.feed), journey.id, journey.journey)),
+        );
+        assert_eq!(restored.len(), 2);
+        let journey = restored.get("VYG:ServiceJourney:1").unwrap();
+        assert_eq!(journey.feed(), "test");
+        assert_eq!(journey.prev_stop_name(), "Oslo S");
+        assert_eq!(journey.recorded_delay_seconds(), 180);
+        assert_eq!(journey.next_stop_name(), Some("Lillestrøm"));
+        // Restored journeys don't count towards the data quality
+        assert_eq!(restored.quality().count(), 0);
+
+        // Saving again replaces the snapshot
+        save(&path, &Journeys::default(), 43, 44).unwrap();
+        let snapshot = load(&path).unwrap().unwrap();
+        assert_eq!(
+            (snapshot.last_successful_sync, snapshot.next_sync),
+            (43, 44)
+        );
+        assert!(snapshot.journeys.is_empty());
+    }
+
+    #[test]
+    fn ignores_missing_and_outdated_snapshots() {
+        let dir = testing::TempDir::new("snapshot-outdated");
+        let path = dir.path().join("snapshot.json.zst");
+        assert!(load(&path).unwrap().is_none());
+
+        let outdated = serde_json::json!({
+            "version": SNAPSHOT_VERSION - 1,
+            "saved_at": "2026-10-18T10:00:00+02:00",
+            "last_successful_sync": 1,
+            "next_sync": 1,
+            "journeys": []
+        });
+        fs::write(
+            &path,
+            zstd::encode_all(outdated.to_string().as_bytes(), 3).unwrap(),
+        )
+        .unwrap();
+        assert!(load(&path).unwrap().is_none());
+
+        fs::write(&path, b"not a snapshot").unwrap();
+        assert!(load(&path).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+// Where the stop registry comes from, with an optional local cache of the parquet files
+use anyhow::{Context, bail};
+use reqwest::Client;
+use reqwest::StatusCode;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const FILES: [&str; 2] = ["quays.parquet", "stops.parquet"];
+
+/// Stored next to each cached file, so that we only download files that changed
+#[derive(Serialize, Deserialize, Default)]
+struct CacheMetadata {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+pub struct StopSource {
+    parquet_root: String,
+    cache_dir: Option<PathBuf>,
+    client: Client,
+}
+
+/// The directory to read the parquet files from
+pub struct PreparedStops {
+    pub root: String,
+    /// The remote was unavailable, so these are the files we downloaded earlier
+    pub from_cache: bool,
+}
+
+impl StopSource {
+    pub fn new(parquet_root: String, cache_dir: Option<String>, client: Client) -> Self {
+        Self {
+            parquet_root,
+            cache_dir: cache_dir.map(PathBuf::from),
+            client,
+        }
+    }
+
+    fn is_remote(&self) -> bool {
+        self.parquet_root.starts_with("http://") || self.parquet_root.starts_with("https://")
+    }
+
+    /// Copy the parquet files from `dir` into the cache, unless it already has them. This lets us
+    /// boot without network access the first time, eg. with files baked into a container image.
+    pub fn seed(&self, dir: &str) -> anyhow::Result<()> {
+        let Some(cache_dir) = &self.cache_dir else {
+            bail!("Seeding the parquet cache requires --parquet-cache-dir");
+        };
+        fs::create_dir_all(cache_dir)?;
+        for file in FILES {
+            let target = cache_dir.join(file);
+            if target.exists() {
+                info!("{} is already cached, not seeding it", target.display());
+                continue;
+            }
+            let source = Path::new(dir).join(file);
+            fs::copy(&source, &target)
+                .with_context(|| format!("Unable to seed the cache with {}", source.display()))?;
+            info!("Seeded {} from {}", target.display(), source.display());
+        }
+        Ok(())
+    }
+
+    /// Download the parquet files that changed since last time into the cache, and tell where to
+    /// read them from. The files in the cache are only replaced when every download succeeded, so
+    /// that we never load quays and stops from different versions. When the remote is
+    /// unavailable, we use the cached files if we have them.
+    pub async fn prepare(&self) -> anyhow::Result<PreparedStops> {
+        let Some(cache_dir) = self.cache_dir.as_ref().filter(|_| self.is_remote()) else {
+            return Ok(PreparedStops {
+                root: self.parquet_root.clone(),
+                from_cache: false,
+            });
+        };
+        tokio::fs::create_dir_all(cache_dir).await?;
+        let mut downloaded = Vec::new();
+        let mut failed = None;
+        for file in FILES {
+            match self.download(cache_dir, file).await {
+                Ok(Some(download)) => downloaded.push(download),
+                Ok(None) => {}
+                Err(reason) => {
+                    failed = Some((file, reason));
+                    break;
+                }
+            }
+        }
+
+        if let Some((file, reason)) = failed {
+            for download in downloaded {
+                let _ = tokio::fs::remove_file(&download.partial).await;
+            }
+            for file in FILES {
+                if !tokio::fs::try_exists(cache_dir.join(file)).await? {
+                    return Err(reason.context(format!("{file} is not in the cache either")));
+                }
+            }
+            warn!("Unable to download {file}, using the cached files: {reason:?}");
+            return Ok(PreparedStops {
+                root: cache_dir.display().to_string(),
+                from_cache: true,
+            });
+        }
+
+        for download in downloaded {
+            tokio::fs::rename(&download.partial, &download.target).await?;
+            tokio::fs::write(&download.metadata_path, &download.metadata).await?;
+            info!("Cached {}", download.target.display());
+        }
+        Ok(PreparedStops {
+            root: cache_dir.display().to_string(),
+            from_cache: false,
+        })
+    }
+
+    /// Download `file` next to where it belongs in the cache, unless it hasn't changed since we
+    /// cached it
+    async fn download(&self, cache_dir: &Path, file: &str) -> anyhow::Result<Option<Download>> {
+        let url = format!("{}/{file}", self.parquet_root.trim_end_matches('/'));
+        let target = cache_dir.join(file);
+        let metadata_path = cache_dir.join(format!("{file}.json"));
+        // Without the file, the metadata is useless, and the remote would tell us it hasn't changed
+        let metadata: CacheMetadata = if tokio::fs::try_exists(&target).await? {
+            tokio::fs::read(&metadata_path)
+                .await
+                .ok()
+                .and_then(|content| serde_json::from_slice(&content).ok())
+                .unwrap_or_default()
+        } else {
+            CacheMetadata::default()
+        };
+
+        let mut request = self.client.get(url.as_str());
+        if let Some(etag) = &metadata.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &metadata.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            info!("{url} has not changed since we cached it");
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        let metadata = CacheMetadata {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        let content = response.bytes().await?;
+
+        let partial = cache_dir.join(format!("{file}.partial"));
+        tokio::fs::write(&partial, &content).await?;
+        Ok(Some(Download {
+            partial,
+            target,
+            metadata_path,
+            metadata: serde_json::to_vec(&metadata)?,
+        }))
+    }
+}
+
+/// A file we downloaded, which replaces the cached one once the other files have been downloaded
+struct Download {
+    partial: PathBuf,
+    target: PathBuf,
+    metadata_path: PathBuf,
+    metadata: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use axum::Router;
+    use axum::extract::{Path as UrlPath, State};
+    use axum::http::HeaderMap;
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::get;
+    use fxhash::FxHashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Serves the parquet files, with an ETag, and fails for files it doesn't have
+    #[derive(Clone, Default)]
+    struct Remote {
+        /// The content and ETag of each file
+        files: Arc<Mutex<FxHashMap<String, (String, String)>>>,
+        /// The If-None-Match of each request
+        requests: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    async fn serve(
+        State(remote): State<Remote>,
+        UrlPath(file): UrlPath<String>,
+        headers: HeaderMap,
+    ) -> Response {
+        let if_none_match = headers
+            .get(IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+        remote.requests.lock().unwrap().push(if_none_match.clone());
+        match remote.files.lock().unwrap().get(&file) {
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            Some((_, etag)) if if_none_match.as_ref() == Some(etag) => {
+                StatusCode::NOT_MODIFIED.into_response()
+            }
+            Some((content, etag)) => ([(ETAG, etag.clone())], content.clone()).into_response(),
+        }
+    }
+
+    impl Remote {
+        async fn start() -> (Self, String) {
+            let remote = Self::default();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/parquet/", listener.local_addr().unwrap());
+            let app = Router::new()
+                .route("/parquet/{file}", get(serve))
+                .with_state(remote.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            (remote, url)
+        }
+
+        fn publish(&self, version: &str) {
+            let mut files = self.files.lock().unwrap();
+            for file in FILES {
+                files.insert(
+                    file.to_string(),
+                    (format!("{file} {version}"), format!("\"{version}\"")),
+                );
+            }
+        }
+
+        fn fail(&self, file: &str) {
+            self.files.lock().unwrap().remove(file);
+        }
+
+        fn take_requests(&self) -> Vec<Option<String>> {
+            std::mem::take(&mut self.requests.lock().unwrap())
+        }
+    }
+
+    fn cached(cache: &TempDir) -> Vec<String> {
+        FILES
+            .iter()
+            .map(|file| fs::read_to_string(cache.path().join(file)).unwrap_or_default())
+            .collect()
+    }
+
+    fn leftovers(cache: &TempDir) -> Vec<String> {
+        fs::read_dir(cache.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.ends_with(".partial"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replaces_both_files_together_and_falls_back_to_the_cache() {
+        let (remote, url) = Remote::start().await;
+        let cache = TempDir::new("stop-source-cache");
+        let cache_dir = cache.path().display().to_string();
+        let source = StopSource::new(url, Some(cache_dir.clone()), Client::new());
+
+        remote.publish("v1");
+        let prepared = source.prepare().await.unwrap();
+        assert_eq!(prepared.root, cache_dir);
+        assert!(!prepared.from_cache);
+        assert_eq!(cached(&cache), ["quays.parquet v1", "stops.parquet v1"]);
+        assert!(
+            remote
+                .take_requests()
+                .iter()
+                .all(|if_none_match| if_none_match.is_none())
+        );
+
+        // Unchanged files aren't downloaded again
+        let prepared = source.prepare().await.unwrap();
+        assert!(!prepared.from_cache);
+        assert_eq!(cached(&cache), ["quays.parquet v1", "stops.parquet v1"]);
+        let requests = remote.take_requests();
+        assert_eq!(requests.len(), 2);
+        assert!(
+            requests
+                .iter()
+                .all(|if_none_match| if_none_match.as_deref() == Some("\"v1\""))
+        );
+
+        // The quays change, but the stops are unavailable, so we keep the old quays as well
+        remote.publish("v2");
+        remote.fail("stops.parquet");
+        let prepared = source.prepare().await.unwrap();
+        assert_eq!(prepared.root, cache_dir);
+        assert!(prepared.from_cache);
+        assert_eq!(cached(&cache), ["quays.parquet v1", "stops.parquet v1"]);
+        assert!(leftovers(&cache).is_empty());
+
+        // Until both can be downloaded
+        remote.publish("v2");
+        let prepared = source.prepare().await.unwrap();
+        assert!(!prepared.from_cache);
+        assert_eq!(cached(&cache), ["quays.parquet v2", "stops.parquet v2"]);
+        assert!(leftovers(&cache).is_empty());
+    }
+
+    #[tokio::test]
+    async fn fails_without_the_remote_or_a_cache() {
+        let (remote, url) = Remote::start().await;
+        let cache = TempDir::new("stop-source-empty-cache");
+        let source = StopSource::new(url, Some(cache.path().display().to_string()), Client::new());
+        remote.publish("v1");
+        remote.fail("stops.parquet");
+        assert!(source.prepare().await.is_err());
+        assert_eq!(cached(&cache), ["", ""]);
+        assert!(leftovers(&cache).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_local_files_in_place() {
+        let source = StopSource::new("data/parquet".to_string(), None, Client::new());
+        let prepared = source.prepare().await.unwrap();
+        assert_eq!(prepared.root, "data/parquet");
+        assert!(!prepared.from_cache);
+    }
+}
@@ -0,0 +1,155 @@
+// Configuration of when journeys are considered possibly stuck, or their data stale
+use anyhow::bail;
+use chrono::TimeDelta;
+use serde::Deserialize;
+use std::fs;
+use tracing::info;
+
+/// The contents of the file passed to `--stuck-rules`, in TOML. The top level thresholds apply to
+/// all journeys, and each matching rule overrides them in order.
+#[derive(Deserialize)]
+pub struct StuckConfig {
+    /// How long after the planned arrival at the next stop before a journey is possibly stuck
+    #[serde(default = "default_cushion_minutes")]
+    cushion_minutes: u32,
+    /// How long without updates from the producer before we consider the data stale rather than
+    /// the vehicle stuck
+    #[serde(default = "default_stale_after_minutes")]
+    stale_after_minutes: u32,
+    #[serde(default)]
+    rules: Vec<StuckOverride>,
+}
+
+fn default_cushion_minutes() -> u32 {
+    8
+}
+
+fn default_stale_after_minutes() -> u32 {
+    10
+}
+
+#[derive(Deserialize)]
+struct StuckOverride {
+    /// Only apply to journeys with this vehicle mode, eg. bus
+    mode: Option<String>,
+    /// Only apply to journeys from this data source, eg. VYG
+    data_source: Option<String>,
+    cushion_minutes: Option<u32>,
+    stale_after_minutes: Option<u32>,
+}
+
+/// The thresholds that apply to a particular journey
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StuckRule {
+    pub cushion: TimeDelta,
+    pub stale_after: TimeDelta,
+}
+
+impl Default for StuckConfig {
+    fn default() -> Self {
+        Self {
+            cushion_minutes: default_cushion_minutes(),
+            stale_after_minutes: default_stale_after_minutes(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl StuckConfig {
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let config = Self::from_toml(&fs::read_to_string(path)?)?;
+        info!("Loaded {} stuck rules from {path}", config.rules.len());
+        Ok(config)
+    }
+
+    pub(crate) fn from_toml(content: &str) -> anyhow::Result<Self> {
+        let config: StuckConfig = toml::from_str(content)?;
+        if let Some(i) = config
+            .rules
+            .iter()
+            .position(|rule| rule.mode.is_none() && rule.data_source.is_none())
+        {
+            bail!("Stuck rule number {} needs a mode or a data_source", i + 1);
+        }
+        Ok(config)
+    }
+
+    pub fn rule_for(&self, mode: &str, data_source: &str) -> StuckRule {
+        let mut cushion_minutes = self.cushion_minutes;
+        let mut stale_after_minutes = self.stale_after_minutes;
+        for rule in self.rules.iter().filter(|rule| {
+            rule.mode.as_ref().is_none_or(|m| m == mode)
+                && rule.data_source.as_ref().is_none_or(|ds| ds == data_source)
+        }) {
+            cushion_minutes = rule.cushion_minutes.unwrap_or(cushion_minutes);
+            stale_after_minutes = rule.stale_after_minutes.unwrap_or(stale_after_minutes);
+        }
+        StuckRule {
+            cushion: TimeDelta::minutes(cushion_minutes as i64),
+            stale_after: TimeDelta::minutes(stale_after_minutes as i64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(cushion_minutes: i64, stale_after_minutes: i64) -> StuckRule {
+        StuckRule {
+            cushion: TimeDelta::minutes(cushion_minutes),
+            stale_after: TimeDelta::minutes(stale_after_minutes),
+        }
+    }
+
+    #[test]
+    fn defaults_apply_without_rules() {
+        assert_eq!(StuckConfig::default().rule_for("rail", "VYG"), rule(8, 10));
+        let config = StuckConfig::from_toml("cushion_minutes = 3").unwrap();
+        assert_eq!(config.rule_for("bus", "RUT"), rule(3, 10));
+    }
+
+    #[test]
+    fn later_matching_rules_take_precedence() {
+        let config = StuckConfig::from_toml(
+            r#"
+            cushion_minutes = 8
+            stale_after_minutes = 10
+
+            [[rules]]
+            mode = "bus"
+            cushion_minutes = 4
+
+            [[rules]]
+            data_source = "VYG"
+            stale_after_minutes = 5
+
+            [[rules]]
+            mode = "bus"
+            data_source = "VYG"
+            cushion_minutes = 6
+            "#,
+        )
+        .unwrap();
+        // Per mode, per data source, both, and neither
+        assert_eq!(config.rule_for("bus", "RUT"), rule(4, 10));
+        assert_eq!(config.rule_for("rail", "VYG"), rule(8, 5));
+        assert_eq!(config.rule_for("bus", "VYG"), rule(6, 5));
+        assert_eq!(config.rule_for("rail", "GOA"), rule(8, 10));
+    }
+
+    #[test]
+    fn rules_must_say_what_they_apply_to() {
+        let error = StuckConfig::from_toml(
+            r#"
+            [[rules]]
+            mode = "bus"
+            cushion_minutes = 4
+
+            [[rules]]
+            cushion_minutes = 4
+            "#,
+        );
+        assert!(error.is_err_and(|e| e.to_string().contains("number 2")));
+    }
+}
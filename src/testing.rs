@@ -0,0 +1,110 @@
+// Journeys to build in tests, without a stop registry or a feed, and other fixtures
+use crate::membased::{JourneySource, Journeys, JourneysBuilder, SourceCall, Stops};
+use crate::stuck::StuckConfig;
+use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub fn minutes_from_now(minutes: i64) -> DateTime<FixedOffset> {
+    (Utc::now() + TimeDelta::minutes(minutes)).fixed_offset()
+}
+
+/// A call at `name` that is expected on time. The stop isn't in the registry, so the journey
+/// falls back to the StopPointName.
+pub fn call(name: &str, time: DateTime<FixedOffset>) -> SourceCall {
+    SourceCall {
+        stop_point_ref: Some(format!("NSR:Quay:{name}")),
+        stop_point_name: Some(name.to_string()),
+        aimed_arrival_time: Some(time),
+        aimed_departure_time: Some(time),
+        expected_arrival_time: Some(time),
+        expected_departure_time: Some(time),
+        actual_arrival_time: None,
+        actual_departure_time: None,
+        cancelled: false,
+        prediction_inaccurate: false,
+        low_quality: false,
+        arrival_platform_name: None,
+        departure_platform_name: None,
+        arrival_quay_change: None,
+        departure_quay_change: None,
+    }
+}
+
+/// A recorded call at `name`, which the vehicle visited `delay` after `time`
+pub fn visited(name: &str, time: DateTime<FixedOffset>, delay: TimeDelta) -> SourceCall {
+    SourceCall {
+        actual_arrival_time: Some(time + delay),
+        actual_departure_time: Some(time + delay),
+        ..call(name, time)
+    }
+}
+
+pub fn cancelled(call: SourceCall) -> SourceCall {
+    SourceCall {
+        cancelled: true,
+        ..call
+    }
+}
+
+/// A monitored rail journey on `line_ref`, eg. `VYG:Line:R10`, recorded just now. The data source
+/// is the first part of the line.
+pub fn source(
+    line_ref: &str,
+    recorded: Vec<SourceCall>,
+    estimated: Vec<SourceCall>,
+) -> JourneySource {
+    JourneySource {
+        recorded_at_time: Utc::now().fixed_offset(),
+        data_source: line_ref.split(':').next().unwrap().to_string(),
+        line_ref: line_ref.to_string(),
+        mode: Some("rail".to_string()),
+        cancelled: false,
+        extra: false,
+        monitored: true,
+        prediction_inaccurate: false,
+        recorded,
+        estimated,
+    }
+}
+
+/// Journeys with the ids and sources in `journeys`, linked to their replacements
+pub fn journeys<'a>(journeys: impl IntoIterator<Item = (&'a str, JourneySource)>) -> Journeys {
+    journeys_with(&StuckConfig::default(), journeys)
+}
+
+pub fn journeys_with<'a>(
+    stuck: &StuckConfig,
+    journeys: impl IntoIterator<Item = (&'a str, JourneySource)>,
+) -> Journeys {
+    let stops = Stops::new(Vec::new(), Vec::new());
+    let mut builder = JourneysBuilder::new(&stops, stuck, Arc::from("test"));
+    for (id, source) in journeys {
+        builder.add_source(Some(id.to_string()), source);
+    }
+    let mut journeys = builder.build();
+    journeys.link_replacements();
+    journeys
+}
+
+/// A directory of its own for each test, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("forsinka-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}